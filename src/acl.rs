@@ -0,0 +1,452 @@
+use crate::auth::Identity;
+use crate::error::MyError;
+use crate::socks::{Address, Cmd, Destination};
+use clap::ArgEnum;
+use ipnet::IpNet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ArgEnum)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl FromStr for Action {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        })
+    }
+}
+
+/// Matches a domain name.
+/// `example.com` matches only itself, `.example.com` matches it and every
+/// subdomain, and any pattern containing `*` is a wildcard where `*` matches
+/// any run of characters.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum DomainPattern {
+    Exact(String),
+    Suffix(String),
+    Wildcard(String),
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl DomainPattern {
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+
+        match self {
+            DomainPattern::Exact(name) => domain == *name,
+            DomainPattern::Suffix(suffix) => {
+                domain == suffix[1..] || domain.ends_with(suffix.as_str())
+            }
+            DomainPattern::Wildcard(pattern) => wildcard_match(pattern, &domain),
+        }
+    }
+}
+
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one item
+    let first = parts.next().unwrap();
+
+    let mut rest = match s.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    // no '*' in the pattern
+    rest.is_empty()
+}
+
+impl FromStr for DomainPattern {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = normalize(s);
+
+        if s.is_empty() {
+            Err(MyError::Parse)
+        } else if s.contains('*') {
+            Ok(DomainPattern::Wildcard(s))
+        } else if s.starts_with('.') {
+            Ok(DomainPattern::Suffix(s))
+        } else {
+            Ok(DomainPattern::Exact(s))
+        }
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomainPattern::Exact(s) | DomainPattern::Suffix(s) | DomainPattern::Wildcard(s) => {
+                f.write_str(s)
+            }
+        }
+    }
+}
+
+/// An inclusive range of ports, written `443` or `1024-65535`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None => {
+                let port = s.parse()?;
+                (port, port)
+            }
+        };
+
+        if start > end {
+            return Err(MyError::Parse);
+        }
+
+        Ok(PortRange { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// What a rule is matched against
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    pub client: IpAddr,
+    pub user: Option<&'a Identity>,
+    pub dest: &'a Destination,
+    /// What a domain destination resolved to, empty when it wasn't looked
+    /// up. `dst` conditions match these too.
+    pub resolved: &'a [IpAddr],
+    pub cmd: Cmd,
+}
+
+/// The match conditions shared by access rules and routes. Every condition
+/// that is set must match, and a condition with several values matches if
+/// any of them does. `dsts` and `domains` together form one destination
+/// condition, so both IP ranges and names can be listed.
+///
+/// Written as `key=value[,value...]` words, with keys `client`, `user`, `dst`,
+/// `domain`, `port` and `cmd`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Conditions {
+    pub clients: Vec<IpNet>,
+    pub users: Vec<String>,
+    pub dsts: Vec<IpNet>,
+    pub domains: Vec<DomainPattern>,
+    pub ports: Vec<PortRange>,
+    pub cmds: Vec<Cmd>,
+}
+
+impl Conditions {
+    /// Match everything except the destination and port
+    fn matches_source(&self, req: &Request) -> bool {
+        (self.clients.is_empty() || in_nets(&self.clients, req.client))
+            && (self.users.is_empty() || req.user.is_some_and(|id| self.users.contains(&id.user)))
+            && (self.cmds.is_empty() || self.cmds.contains(&req.cmd))
+    }
+
+    fn has_destination(&self) -> bool {
+        !self.dsts.is_empty() || !self.domains.is_empty() || !self.ports.is_empty()
+    }
+
+    fn matches_destination(&self, dest: &Destination, resolved: &[IpAddr]) -> bool {
+        let in_dsts = |ip: &IpAddr| in_nets(&self.dsts, *ip);
+
+        let addr = if self.dsts.is_empty() && self.domains.is_empty() {
+            true
+        } else {
+            match &dest.addr {
+                Address::IP(ip) => in_dsts(ip),
+                Address::Name(name) => {
+                    self.domains.iter().any(|d| d.matches(name)) || resolved.iter().any(in_dsts)
+                }
+            }
+        };
+
+        addr && (self.ports.is_empty() || self.ports.iter().any(|p| p.contains(dest.port)))
+    }
+
+    pub fn matches(&self, req: &Request) -> bool {
+        self.matches_source(req) && self.matches_destination(req.dest, req.resolved)
+    }
+
+    /// Parse whitespace separated `key=value[,value...]` words
+    pub fn parse<'a>(words: impl Iterator<Item = &'a str>) -> Result<Self, MyError> {
+        let mut conditions = Conditions::default();
+
+        for word in words {
+            let (key, values) = word.split_once('=').ok_or(MyError::Parse)?;
+
+            match key {
+                "client" => conditions.clients.extend(parse_nets(values)?),
+                "user" => conditions.users.extend(parse_list::<String>(values)?),
+                "dst" => conditions.dsts.extend(parse_nets(values)?),
+                "domain" => conditions
+                    .domains
+                    .extend(parse_list::<DomainPattern>(values)?),
+                "port" => conditions.ports.extend(parse_list::<PortRange>(values)?),
+                "cmd" => conditions.cmds.extend(parse_list::<Cmd>(values)?),
+                _ => return Err(MyError::Parse),
+            }
+        }
+
+        Ok(conditions)
+    }
+}
+
+/// Whether `ip` is in any of `nets`, taking an IPv4-mapped IPv6 address as
+/// the IPv4 address it reaches and an unspecified address as loopback, which
+/// is where connections to it go
+fn in_nets(nets: &[IpNet], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let reaches = match ip {
+        IpAddr::V4(v4) if v4.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(v6) if v6.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        _ => ip,
+    };
+
+    nets.iter()
+        .any(|net| net.contains(&ip) || net.contains(&reaches))
+}
+
+/// Parse a network, where a bare address is taken as a single host
+fn parse_net(s: &str) -> Result<IpNet, MyError> {
+    match s.parse::<IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => Ok(IpNet::from(
+            s.parse::<IpAddr>().map_err(|_| MyError::Parse)?,
+        )),
+    }
+}
+
+fn parse_nets(values: &str) -> Result<Vec<IpNet>, MyError> {
+    values.split(',').map(|v| parse_net(v.trim())).collect()
+}
+
+fn parse_list<T: FromStr>(values: &str) -> Result<Vec<T>, MyError> {
+    values
+        .split(',')
+        .map(|v| v.trim().parse().map_err(|_| MyError::Parse))
+        .collect()
+}
+
+/// A single access rule, written as the action followed by its conditions, e.g.
+/// `deny client=10.0.0.0/8 dst=169.254.0.0/16,127.0.0.0/8 port=1-1023 cmd=connect`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rule {
+    pub action: Action,
+    pub conditions: Conditions,
+}
+
+impl Rule {
+    pub fn new(action: Action, conditions: Conditions) -> Self {
+        Rule { action, conditions }
+    }
+
+    pub fn matches(&self, req: &Request) -> bool {
+        self.conditions.matches(req)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let action = words.next().ok_or(MyError::Parse)?.parse()?;
+
+        Ok(Rule::new(action, Conditions::parse(words)?))
+    }
+}
+
+/// An ordered list of rules where the first match decides
+#[derive(Debug, Clone)]
+pub struct Acl {
+    pub rules: Vec<Rule>,
+    /// Used when no rule matches
+    pub default: Action,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl {
+            rules: Vec::new(),
+            default: Action::Allow,
+        }
+    }
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>, default: Action) -> Self {
+        Acl { rules, default }
+    }
+
+    pub fn check(&self, req: &Request) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(req))
+            .map_or(self.default, |rule| rule.action)
+    }
+
+    /// Whether every destination is denied for this client, user and command.
+    /// Used for UDP associations, which are checked per datagram but should
+    /// be refused up front when nothing could ever be relayed.
+    pub fn denies_all(&self, client: IpAddr, user: Option<&Identity>, cmd: Cmd) -> bool {
+        // the destination is ignored by matches_source
+        let dest = Destination {
+            addr: Address::IP(client),
+            port: 0,
+        };
+        let req = Request {
+            client,
+            user,
+            dest: &dest,
+            resolved: &[],
+            cmd,
+        };
+
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.conditions.matches_source(&req))
+        {
+            if !rule.conditions.has_destination() {
+                return rule.action == Action::Deny;
+            }
+            if rule.action == Action::Allow {
+                return false;
+            }
+        }
+
+        self.default == Action::Deny
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(rules: &[&str]) -> Acl {
+        Acl::new(
+            rules.iter().map(|r| r.parse().unwrap()).collect(),
+            Action::Allow,
+        )
+    }
+
+    fn check(acl: &Acl, addr: Address, resolved: &[IpAddr]) -> Action {
+        acl.check(&Request {
+            client: "192.0.2.1".parse().unwrap(),
+            user: None,
+            dest: &Destination { addr, port: 80 },
+            resolved,
+            cmd: Cmd::Connect,
+        })
+    }
+
+    fn ip(s: &str) -> Address {
+        Address::IP(s.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        let acl = acl(&["deny dst=127.0.0.0/8,169.254.0.0/16"]);
+
+        assert_eq!(check(&acl, ip("127.0.0.1"), &[]), Action::Deny);
+        assert_eq!(check(&acl, ip("::ffff:127.0.0.1"), &[]), Action::Deny);
+        assert_eq!(check(&acl, ip("::ffff:169.254.169.254"), &[]), Action::Deny);
+        assert_eq!(check(&acl, ip("::ffff:192.0.2.1"), &[]), Action::Allow);
+    }
+
+    #[test]
+    fn resolved_ipv4_mapped_addresses_match_ipv4_ranges() {
+        let acl = acl(&["deny dst=169.254.0.0/16"]);
+        let name = Address::Name("metadata.example".to_owned());
+
+        let mapped = ["::ffff:169.254.169.254".parse().unwrap()];
+        assert_eq!(check(&acl, name.clone(), &mapped), Action::Deny);
+
+        let public = ["2001:db8::1".parse().unwrap()];
+        assert_eq!(check(&acl, name, &public), Action::Allow);
+    }
+
+    #[test]
+    fn unspecified_addresses_match_loopback() {
+        let acl = acl(&["deny dst=127.0.0.0/8,::1/128"]);
+
+        assert_eq!(check(&acl, ip("0.0.0.0"), &[]), Action::Deny);
+        assert_eq!(check(&acl, ip("::"), &[]), Action::Deny);
+        assert_eq!(check(&acl, ip("::ffff:0.0.0.0"), &[]), Action::Deny);
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_ranges() {
+        let acl = acl(&["deny client=10.0.0.0/8"]);
+        let dest = Destination {
+            addr: ip("192.0.2.1"),
+            port: 80,
+        };
+        let req = Request {
+            client: "::ffff:10.1.2.3".parse().unwrap(),
+            user: None,
+            dest: &dest,
+            resolved: &[],
+            cmd: Cmd::Connect,
+        };
+
+        assert_eq!(acl.check(&req), Action::Deny);
+    }
+}
@@ -0,0 +1,521 @@
+use crate::error::MyError;
+use crate::proxy::{ACCEPT_BACKOFF_MAX, ACCEPT_BACKOFF_MIN};
+use crate::server::{Server, Session};
+use crate::socks::Destination;
+use async_trait::async_trait;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+/// Where the admin API listens, written `127.0.0.1:9001` or
+/// `unix:/run/socks-proxy-server/admin.sock`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for AdminAddr {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(MyError::Parse),
+            Some(path) => Ok(AdminAddr::Unix(PathBuf::from(path))),
+            None => Ok(AdminAddr::Tcp(s.parse().map_err(|_| MyError::Parse)?)),
+        }
+    }
+}
+
+impl fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminAddr::Tcp(addr) => write!(f, "{}", addr),
+            AdminAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A session as listed by the API
+#[derive(Debug, Serialize)]
+struct SessionInfo<'a> {
+    id: u64,
+    listener: SocketAddr,
+    client: SocketAddr,
+    destination: String,
+    /// Our end of the outbound connection
+    outbound: SocketAddr,
+    /// What the outbound connection is connected to, an upstream proxy when
+    /// the session is routed through one
+    remote: SocketAddr,
+    user: Option<&'a str>,
+    route: Option<String>,
+    sent: u64,
+    received: u64,
+    /// Unix timestamps in seconds
+    started: u64,
+    last_activity: u64,
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl<'a> SessionInfo<'a> {
+    fn new(listener: SocketAddr, s: &'a Session) -> Self {
+        SessionInfo {
+            id: s.id,
+            listener,
+            client: s.client2server,
+            destination: s.destination.to_string(),
+            outbound: s.server2remote,
+            remote: s.remote2server,
+            user: s.user.as_ref().map(|id| id.user.as_str()),
+            route: s.route.as_ref().map(|r| r.to_string()),
+            sent: s.traffic.sent(),
+            received: s.traffic.received(),
+            started: unix_secs(s.traffic.started()),
+            last_activity: unix_secs(s.traffic.last_activity()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Terminated {
+    terminated: usize,
+}
+
+/// Which sessions a request applies to, from the `id`, `user` and `dest`
+/// query parameters. Every one given must match.
+#[derive(Debug, Default)]
+struct Filter {
+    id: Option<u64>,
+    user: Option<String>,
+    dest: Option<Destination>,
+}
+
+impl Filter {
+    fn parse(query: Option<&str>) -> Result<Self, MyError> {
+        let mut filter = Filter::default();
+
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "id" => filter.id = Some(value.parse()?),
+                "user" => filter.user = Some(value.into_owned()),
+                "dest" => filter.dest = Some(value.parse()?),
+                _ => return Err(MyError::Parse),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.id.is_none() && self.user.is_none() && self.dest.is_none()
+    }
+
+    fn matches(&self, s: &Session) -> bool {
+        self.id.is_none_or(|id| s.id == id)
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| s.user.as_ref().is_some_and(|id| id.user == *user))
+            && self.dest.as_ref().is_none_or(|dest| s.destination == *dest)
+    }
+}
+
+/// Local HTTP/JSON API to list and terminate sessions.
+///
+/// - `GET /sessions` lists active sessions
+/// - `DELETE /sessions/<id>` terminates one session
+/// - `DELETE /sessions?user=<user>&dest=<host:port>` terminates every match
+///
+/// `GET` takes the same filters. There is no authentication, so only bind it
+/// to a loopback address or a Unix socket.
+#[derive(Debug, Clone)]
+pub struct Admin {
+    servers: Arc<Vec<Arc<Server>>>,
+}
+
+impl Admin {
+    /// Serve the sessions of `servers`, usually one per listener
+    pub fn new(servers: Vec<Arc<Server>>) -> Self {
+        Admin {
+            servers: Arc::new(servers),
+        }
+    }
+
+    /// Accept requests on `addr` until `shutdown` resolves
+    pub async fn serve(
+        self,
+        addr: &AdminAddr,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), MyError> {
+        match addr {
+            AdminAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                self.accept_loop(listener, shutdown).await
+            }
+            #[cfg(unix)]
+            AdminAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                self.accept_loop(listener, shutdown).await
+            }
+            #[cfg(not(unix))]
+            AdminAddr::Unix(_) => Err(MyError::IO),
+        }
+    }
+
+    async fn accept_loop<L: Listener>(
+        self,
+        listener: L,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), MyError> {
+        tokio::pin!(shutdown);
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                accepted = listener.accept_stream() => match accepted {
+                    Ok(stream) => {
+                        backoff = ACCEPT_BACKOFF_MIN;
+                        tokio::spawn(self.clone().connection(stream));
+                    }
+                    Err(e) => {
+                        // like the proxy listeners, wait out running out of
+                        // file descriptors rather than giving up
+                        warn!(
+                            error = %e,
+                            retry_ms = backoff.as_millis() as u64,
+                            "couldn't accept admin connection"
+                        );
+
+                        tokio::select! {
+                            _ = &mut shutdown => return Ok(()),
+                            _ = sleep(backoff) => {}
+                        }
+                        backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    }
+                },
+            }
+        }
+    }
+
+    async fn connection<S>(self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(move |req| {
+            let admin = self.clone();
+            async move { Ok::<_, Infallible>(admin.respond(req)) }
+        });
+
+        if let Err(e) = Http::new()
+            .http1_only(true)
+            .serve_connection(stream, service)
+            .await
+        {
+            debug!(error = %e, "admin connection failed");
+        }
+    }
+
+    fn respond(&self, req: Request<Body>) -> Response<Body> {
+        let mut filter = match Filter::parse(req.uri().query()) {
+            Ok(filter) => filter,
+            Err(_) => return status(StatusCode::BAD_REQUEST),
+        };
+
+        let path = req.uri().path().trim_end_matches('/');
+
+        if let Some(id) = path.strip_prefix("/sessions/") {
+            match id.parse() {
+                Ok(id) => filter.id = Some(id),
+                Err(_) => return status(StatusCode::NOT_FOUND),
+            }
+        } else if path != "/sessions" {
+            return status(StatusCode::NOT_FOUND);
+        }
+
+        match *req.method() {
+            Method::GET => self.list(&filter),
+            Method::DELETE if filter.is_empty() => status(StatusCode::BAD_REQUEST),
+            Method::DELETE => {
+                let terminated = self
+                    .servers
+                    .iter()
+                    .map(|server| server.terminate(|s| filter.matches(s)))
+                    .sum();
+
+                if terminated == 0 && filter.id.is_some() {
+                    status(StatusCode::NOT_FOUND)
+                } else {
+                    json(&Terminated { terminated })
+                }
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    fn list(&self, filter: &Filter) -> Response<Body> {
+        let sessions: Vec<(SocketAddr, Session)> = self
+            .servers
+            .iter()
+            .flat_map(|server| {
+                server
+                    .active_sessions()
+                    .into_iter()
+                    .map(|s| (server.listener(), s))
+            })
+            .filter(|(_, s)| filter.matches(s))
+            .collect();
+
+        let infos: Vec<SessionInfo> = sessions
+            .iter()
+            .map(|(listener, s)| SessionInfo::new(*listener, s))
+            .collect();
+
+        json(&infos)
+    }
+}
+
+/// A listener the API takes connections from
+#[async_trait]
+trait Listener: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    async fn accept_stream(&self) -> io::Result<Self::Stream>;
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_stream(&self) -> io::Result<TcpStream> {
+        Ok(self.accept().await?.0)
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_stream(&self) -> io::Result<UnixStream> {
+        Ok(self.accept().await?.0)
+    }
+}
+
+/// Remove a socket left at `path` by an earlier run, which would make bind
+/// fail. Anything else there is left alone and fails the API.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<(), MyError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
+        Ok(_) => {
+            warn!(path = %path.display(), "admin socket path exists and isn't a socket");
+            Err(MyError::IO)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    // our types always serialize
+    let body = serde_json::to_vec(value).unwrap();
+
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Identity;
+    use crate::server::{Peer, ServerConfig};
+    use serde_json::Value;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    fn session(port: u16, dest: &str, user: Option<&str>) -> Session {
+        let client = Peer {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            local: "127.0.0.1:1080".parse().unwrap(),
+        };
+        let remote = Peer {
+            addr: "192.0.2.1:443".parse().unwrap(),
+            local: "192.0.2.100:50000".parse().unwrap(),
+        };
+        Session::new(
+            client,
+            remote,
+            dest.parse().unwrap(),
+            user.map(Identity::new),
+            None,
+        )
+    }
+
+    /// An API over one listener with a session of alice's and an anonymous
+    /// one
+    fn admin() -> (Admin, Session, Session) {
+        let server = Arc::new(Server::new(
+            ServerConfig::default(),
+            "127.0.0.1:1080".parse().unwrap(),
+        ));
+        let alice = session(40000, "example.com:443", Some("alice"));
+        let anonymous = session(40001, "example.org:80", None);
+        server.session_start(alice.clone());
+        server.session_start(anonymous.clone());
+
+        (Admin::new(vec![server]), alice, anonymous)
+    }
+
+    /// Send one request through the API's HTTP service, returning the status
+    /// and body of the response
+    async fn request(admin: &Admin, method: &str, path: &str) -> (u16, String) {
+        let (mut ours, theirs) = duplex(4096);
+        tokio::spawn(admin.clone().connection(theirs));
+
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: admin\r\nConnection: close\r\n\r\n",
+            method, path
+        );
+        ours.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        ours.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_owned())
+    }
+
+    async fn terminated(session: &Session) -> bool {
+        timeout(Duration::from_millis(100), session.terminated())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed() {
+        let (admin, alice, _) = admin();
+
+        let (status, body) = request(&admin, "GET", "/sessions").await;
+        assert_eq!(status, 200);
+        let listed: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+
+        let (status, body) = request(&admin, "GET", "/sessions?user=alice").await;
+        assert_eq!(status, 200);
+        let listed: Value = serde_json::from_str(&body).unwrap();
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["id"], alice.id);
+        assert_eq!(listed[0]["user"], "alice");
+        assert_eq!(listed[0]["destination"], "example.com:443");
+        assert_eq!(listed[0]["client"], "127.0.0.1:40000");
+        assert_eq!(listed[0]["listener"], "127.0.0.1:1080");
+    }
+
+    #[tokio::test]
+    async fn sessions_are_terminated_by_id() {
+        let (admin, alice, anonymous) = admin();
+
+        let path = format!("/sessions/{}", anonymous.id);
+        let (status, body) = request(&admin, "DELETE", &path).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"terminated":1}"#);
+
+        assert!(terminated(&anonymous).await);
+        assert!(!terminated(&alice).await);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_terminated_by_filter() {
+        let (admin, alice, anonymous) = admin();
+
+        let (status, body) = request(&admin, "DELETE", "/sessions?dest=example.com:443").await;
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"terminated":1}"#);
+
+        assert!(terminated(&alice).await);
+        assert!(!terminated(&anonymous).await);
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_not_found() {
+        let (admin, alice, anonymous) = admin();
+
+        let path = format!("/sessions/{}", alice.id.max(anonymous.id) + 1000);
+        assert_eq!(request(&admin, "DELETE", &path).await.0, 404);
+        assert_eq!(request(&admin, "DELETE", "/sessions/alice").await.0, 404);
+        assert_eq!(request(&admin, "GET", "/stats").await.0, 404);
+
+        // nothing was terminated along the way
+        assert!(!terminated(&alice).await);
+        assert!(!terminated(&anonymous).await);
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_refused() {
+        let (admin, alice, _) = admin();
+
+        // terminating everything takes a filter
+        assert_eq!(request(&admin, "DELETE", "/sessions").await.0, 400);
+        assert_eq!(request(&admin, "GET", "/sessions?color=red").await.0, 400);
+        assert_eq!(request(&admin, "POST", "/sessions").await.0, 405);
+
+        assert!(!terminated(&alice).await);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_stale_sockets_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("admin-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let missing = dir.join("missing.sock");
+        assert!(remove_stale_socket(&missing).is_ok());
+
+        let socket = dir.join("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        assert!(remove_stale_socket(&socket).is_ok());
+        assert!(!socket.exists());
+
+        let file = dir.join("config.toml");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
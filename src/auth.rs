@@ -0,0 +1,254 @@
+use crate::error::MyError;
+use crate::passwd::HashedPassword;
+use crate::server::User;
+use crate::watch::reload_on_change;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Who a client authenticated as. Attached to its sessions so later policy
+/// and logging can refer to it.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Identity {
+    pub user: String,
+}
+
+impl Identity {
+    pub fn new(user: impl Into<String>) -> Self {
+        Identity { user: user.into() }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.user)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum AuthDenied {
+    #[error("unknown user")]
+    UnknownUser,
+    #[error("wrong password")]
+    BadPassword,
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Checks SOCKS5 username/password credentials.
+#[async_trait]
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    async fn authenticate(&self, user: &str, pass: &str) -> Result<Identity, AuthDenied>;
+}
+
+/// A fixed list of plaintext users, as given with `--users`
+#[derive(Debug, Clone)]
+pub struct StaticUsers {
+    users: Vec<User>,
+}
+
+impl StaticUsers {
+    pub fn new(users: Vec<User>) -> Self {
+        StaticUsers { users }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticUsers {
+    async fn authenticate(&self, user: &str, pass: &str) -> Result<Identity, AuthDenied> {
+        check(&self.users, user, pass)
+    }
+}
+
+fn check(users: &[User], user: &str, pass: &str) -> Result<Identity, AuthDenied> {
+    match users.iter().find(|v| v.user == user) {
+        Some(v) if v.pass == pass => Ok(Identity::new(user)),
+        Some(_) => Err(AuthDenied::BadPassword),
+        None => Err(AuthDenied::UnknownUser),
+    }
+}
+
+type UserTable = HashMap<String, HashedPassword>;
+
+/// Why a users file couldn't be loaded. Lines are counted from 1.
+#[derive(Debug, Error)]
+pub enum UsersFileError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {0}: expected user:password")]
+    NoPassword(usize),
+    #[error("line {0}: unrecognized password hash")]
+    BadHash(usize),
+}
+
+impl From<UsersFileError> for MyError {
+    fn from(e: UsersFileError) -> Self {
+        match e {
+            UsersFileError::Io(_) => MyError::IO,
+            _ => MyError::Parse,
+        }
+    }
+}
+
+/// Users read from an htpasswd style file with one `user:password` entry per
+/// line, where the password is an argon2, bcrypt or `{SHA}` hash, or
+/// plaintext marked `{PLAIN}`. Blank lines and lines starting with `#` are
+/// ignored.
+#[derive(Debug, Clone)]
+pub struct CredentialsFile {
+    path: PathBuf,
+    users: Arc<RwLock<Arc<UserTable>>>,
+}
+
+impl CredentialsFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, UsersFileError> {
+        let path = path.as_ref().to_owned();
+        let users = read_users(&path)?;
+
+        Ok(CredentialsFile {
+            path,
+            users: Arc::new(RwLock::new(Arc::new(users))),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Re-read the file, keeping the current users if it can't be parsed.
+    pub fn reload(&self) -> Result<(), UsersFileError> {
+        let users = read_users(&self.path)?;
+        *self.users.write().unwrap() = Arc::new(users);
+        Ok(())
+    }
+
+    /// Reload the file in the background whenever it changes or the process
+    /// receives SIGHUP. Must be called from within a tokio runtime; the task
+    /// ends once every clone of this `CredentialsFile` is dropped.
+    pub fn watch(self) -> Self {
+        let path = self.path.clone();
+
+        tokio::spawn(reload_on_change(
+            vec![path.clone()],
+            Arc::downgrade(&self.users),
+            move |users| match read_users(&path) {
+                Ok(new) => {
+                    info!(path = %path.display(), users = new.len(), "reloaded users");
+                    *users.write().unwrap() = Arc::new(new)
+                }
+                Err(e) => warn!(path = %path.display(), error = %e, "couldn't reload users"),
+            },
+        ));
+        self
+    }
+}
+
+fn read_users(path: &Path) -> Result<UserTable, UsersFileError> {
+    parse_users(&std::fs::read_to_string(path)?)
+}
+
+fn parse_users(contents: &str) -> Result<UserTable, UsersFileError> {
+    contents
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let (user, pass) = line
+                .split_once(':')
+                .ok_or(UsersFileError::NoPassword(i + 1))?;
+            let hash = pass.parse().map_err(|_| UsersFileError::BadHash(i + 1))?;
+            Ok((user.to_owned(), hash))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Authenticator for CredentialsFile {
+    async fn authenticate(&self, user: &str, pass: &str) -> Result<Identity, AuthDenied> {
+        // take a snapshot so a reload can't block on a slow hash check
+        let users = self.users.read().unwrap().clone();
+
+        let hash = match users.get(user) {
+            Some(hash) => hash.clone(),
+            None => return Err(AuthDenied::UnknownUser),
+        };
+
+        let verified = if hash.is_slow() {
+            let pass = pass.to_owned();
+            tokio::task::spawn_blocking(move || hash.verify(&pass))
+                .await
+                .unwrap_or(false)
+        } else {
+            hash.verify(pass)
+        };
+
+        if verified {
+            Ok(Identity::new(user))
+        } else {
+            Err(AuthDenied::BadPassword)
+        }
+    }
+}
+
+/// Defers the decision to user code.
+pub struct Callback<F> {
+    f: F,
+}
+
+impl<F, Fut> Callback<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Identity, AuthDenied>> + Send,
+{
+    pub fn new(f: F) -> Self {
+        Callback { f }
+    }
+}
+
+impl<F> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callback").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<F, Fut> Authenticator for Callback<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Identity, AuthDenied>> + Send,
+{
+    async fn authenticate(&self, user: &str, pass: &str) -> Result<Identity, AuthDenied> {
+        (self.f)(user.to_owned(), pass.to_owned()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_are_read_past_comments_and_blank_lines() {
+        let users =
+            parse_users("# users\n\nalice:{PLAIN}secret\n  bob:{PLAIN}hunter2  \n").unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users["alice"].verify("secret"));
+        assert!(users["bob"].verify("hunter2"));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let e = parse_users("# users\nalice:{PLAIN}secret\n\nbob\n").unwrap_err();
+        assert!(matches!(e, UsersFileError::NoPassword(4)));
+        assert_eq!(e.to_string(), "line 4: expected user:password");
+
+        let e = parse_users("alice:{PLAIN}secret\nbob:{MD5}abc\n").unwrap_err();
+        assert!(matches!(e, UsersFileError::BadHash(2)));
+        assert_eq!(e.to_string(), "line 2: unrecognized password hash");
+    }
+}
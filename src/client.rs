@@ -1,37 +1,148 @@
+use crate::acl::Request;
+use crate::auth::Identity;
+use crate::connlimit::ConnPermit;
+use crate::error::MyError;
+use crate::http::{copy_body, read_head, response, HttpRequest, RequestBody};
+use crate::logging::{AccessRecord, Outcome};
+use crate::metrics::ByteCounters;
 use crate::parse::{socks5_auth_request, socks5_connection_request, socks_init};
-use crate::server::User;
+use crate::ratelimit::{Scope, Throttle};
+use crate::router::{route_label, Outbound};
+use crate::server::Server;
+use crate::server::{Peer, PeerInfo, Session, Timeouts, Traffic};
 use crate::socks::{
-    SOCKS4Cmd, SOCKS4Init, SOCKS5AuthMethod, SOCKS5AuthReply, SOCKS5AuthRequest, SOCKS5Cmd,
-    SOCKS5ConnectReply, SOCKS5ConnectRequest, SOCKS5Init, SOCKSInit,
+    Cmd, Destination, SOCKS4Cmd, SOCKS4Init, SOCKS5AuthMethod, SOCKS5AuthReply, SOCKS5AuthRequest,
+    SOCKS5Cmd, SOCKS5ConnectReply, SOCKS5ConnectRequest, SOCKS5Init, SOCKSInit,
 };
-use crate::{Message, MyError, Session};
+use crate::udp::UdpRelay;
 use bytes::{BufMut, BytesMut};
+use futures_util::io::AsyncBufReadExt as _;
 use futures_util::io::BufReader as IoBufReader;
-use nom_bufreader::AsyncParse;
+use nom::IResult;
 use replace_with::replace_with_or_abort;
-use std::io::ErrorKind;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::fmt;
+use std::future::pending;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{copy, split, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast::Sender;
-use tokio::time::timeout;
+use tokio::time::{sleep, timeout};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use tracing::{debug, info, Span};
 
+// size of the buffer used for each direction of a relayed connection
+const RELAY_BUFFER: usize = 8192;
+
+// longest handshake message, which only a SOCKS4 user id could exceed
+const MAX_MESSAGE: usize = 4096;
+
+/// Resolves once `traffic` has been idle for `limit`, never if it is `None`
+async fn idle(traffic: &Traffic, limit: Option<Duration>) {
+    let Some(limit) = limit else {
+        return pending().await;
+    };
+
+    loop {
+        let idle = traffic.idle();
+        if idle >= limit {
+            return;
+        }
+        sleep(limit - idle).await;
+    }
+}
+
+/// Resolves after `limit`, never if it is `None`
+async fn expire(limit: Option<Duration>) {
+    match limit {
+        Some(limit) => sleep(limit).await,
+        None => pending().await,
+    }
+}
+
+/// Resolves with the outcome and reason when `session` has to close before
+/// its relay is done: on termination through the registry or a timeout
+async fn session_ended(session: &Session, timeouts: Timeouts) -> (Outcome, &'static str) {
+    tokio::select! {
+        _ = session.terminated() => (Outcome::Terminated, "terminated"),
+        _ = idle(&session.traffic, timeouts.idle) => (Outcome::Timeout, "idle timeout"),
+        _ = expire(timeouts.max_session) => (Outcome::Timeout, "max session duration"),
+    }
+}
+
+/// A protocol a client can speak, told apart by the first byte it sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Socks4,
+    Socks5,
+    Http,
+    Tls,
+}
+
+impl Protocol {
+    /// SOCKS starts with its version, TLS with a handshake record and HTTP
+    /// with a method name in capitals
+    pub fn detect(first: u8) -> Option<Self> {
+        match first {
+            4 => Some(Protocol::Socks4),
+            5 => Some(Protocol::Socks5),
+            0x16 => Some(Protocol::Tls),
+            b'A'..=b'Z' => Some(Protocol::Http),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Socks4 => "socks4",
+            Protocol::Socks5 => "socks5",
+            Protocol::Http => "http",
+            Protocol::Tls => "tls",
+        })
+    }
+}
+
+/// The protocols a listener speaks. With several enabled each client is
+/// served the one its first byte shows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Protocols {
+    pub socks4: bool,
+    pub socks5: bool,
+    /// HTTP CONNECT and forward proxy
+    pub http: bool,
+}
+
+impl Protocols {
+    pub fn enabled(&self, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Socks4 => self.socks4,
+            Protocol::Socks5 => self.socks5,
+            Protocol::Http => self.http,
+            // a TLS listener takes handshakes off before any protocol is
+            // detected, so one here would be TLS inside TLS
+            Protocol::Tls => false,
+        }
+    }
+}
+
+/// A client connection over any transport, switched between the forms the
+/// handshake parser and the relay need
 #[derive(Debug)]
-pub enum Stream {
-    Default(TcpStream),
-    Parsing(IoBufReader<Compat<TcpStream>>),
-    Split(ReadHalf<TcpStream>, WriteHalf<TcpStream>),
+pub enum Stream<S> {
+    Default(S),
+    Parsing(IoBufReader<Compat<S>>),
+    Split(ReadHalf<S>, WriteHalf<S>),
 }
 
-impl Stream {
-    fn new(s: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    fn new(s: S) -> Self {
         Stream::Default(s)
     }
 
-    fn parser(&mut self) -> &mut IoBufReader<Compat<TcpStream>> {
+    fn parser(&mut self) -> &mut IoBufReader<Compat<S>> {
         replace_with_or_abort(self, |self_| match self_ {
             Stream::Default(def) => Stream::Parsing(IoBufReader::new(def.compat())),
             Stream::Parsing(par) => Stream::Parsing(par),
@@ -48,7 +159,7 @@ impl Stream {
         }
     }
 
-    fn split(&mut self) -> (&mut ReadHalf<TcpStream>, &mut WriteHalf<TcpStream>) {
+    fn split(&mut self) -> (&mut ReadHalf<S>, &mut WriteHalf<S>) {
         replace_with_or_abort(self, |self_| match self_ {
             Stream::Default(def) => {
                 let (r, w) = split(def);
@@ -68,7 +179,62 @@ impl Stream {
         }
     }
 
-    fn default(&mut self) -> &mut TcpStream {
+    /// Parse one handshake message, reading until `p` has all of it. Bytes
+    /// that arrive after the message stay buffered for the next read.
+    async fn parse<P, O>(&mut self, p: P) -> Result<O, MyError>
+    where
+        P: for<'a> Fn(&'a [u8]) -> IResult<&'a [u8], O, MyError>,
+    {
+        let reader = self.parser();
+        // the reader only refills once its buffer is used up, so a message
+        // arriving in pieces is gathered here
+        let mut pending = Vec::new();
+
+        loop {
+            let buf = reader.fill_buf().await?;
+            if buf.is_empty() {
+                // closed partway through
+                return Err(MyError::IO);
+            }
+
+            let start = pending.len();
+            let fetched = buf.len();
+            pending.extend_from_slice(buf);
+
+            match p(&pending) {
+                Ok((rest, message)) => {
+                    reader.consume_unpin(pending.len() - rest.len() - start);
+                    return Ok(message);
+                }
+                Err(nom::Err::Incomplete(_)) if pending.len() < MAX_MESSAGE => {
+                    reader.consume_unpin(fetched)
+                }
+                Err(nom::Err::Incomplete(_)) => return Err(MyError::Parse),
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// The next byte the client sends, without consuming it. `None` once the
+    /// client closed the connection.
+    async fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.parser().fill_buf().await?.first().copied())
+    }
+
+    /// Take what the parser read past the last message, which is lost once
+    /// the stream is used directly
+    fn take_buffered(&mut self) -> Vec<u8> {
+        match self {
+            Stream::Parsing(par) => {
+                let buffered = par.buffer().to_vec();
+                par.consume_unpin(buffered.len());
+                buffered
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn default(&mut self) -> &mut S {
         replace_with_or_abort(self, |self_| match self_ {
             Stream::Default(def) => Stream::Default(def),
             Stream::Parsing(par) => Stream::Default(par.into_inner().into_inner()),
@@ -87,81 +253,278 @@ impl Stream {
 }
 
 #[derive(Debug)]
-pub struct Client {
-    connection: Stream,
-    sender: Sender<Message>,
+pub struct Client<S> {
+    connection: Stream<S>,
+    peer: Peer,
+    server: Arc<Server>,
+    identity: Option<Identity>,
+    access: AccessRecord,
+    handshake_done: bool,
+    /// Places under the connection limits, held until the client is gone
+    permits: Vec<ConnPermit>,
+}
+
+/// Copy until EOF and then shut down the writer, holding to `throttle`.
+/// Bytes are counted as they go so the count is right even when the copy
+/// fails.
+async fn pipe<R, W>(
+    r: &mut R,
+    w: &mut W,
+    throttle: &Throttle,
+    count: impl Fn(u64),
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER];
+
+    loop {
+        let n = match r.read(&mut buf).await {
+            Ok(n) => n,
+            // TLS peers that close without a close_notify, which is no
+            // worse than a TCP close for a relay
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            return w.shutdown().await;
+        }
+        throttle.take(n as u64).await;
+        w.write_all(&buf[..n]).await?;
+        count(n as u64);
+    }
 }
 
-impl Client {
-    pub fn new(s: TcpStream, sender: Sender<Message>) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
+    /// Serve a client over any transport, TCP or TLS as well as Unix
+    /// sockets or in-memory streams, with its addresses given by `peer`
+    pub fn new(s: S, peer: Peer, server: Arc<Server>) -> Self {
         Client {
             connection: Stream::new(s),
-            sender,
+            peer,
+            server,
+            identity: None,
+            access: AccessRecord::new(Some(peer.addr)),
+            handshake_done: false,
+            permits: Vec::new(),
         }
     }
 
-    pub fn default(&mut self) -> &mut TcpStream {
+    /// Hold the place `Server::admit` gave this client while it is served
+    pub fn admitted(&mut self, permit: ConnPermit) {
+        self.permits.push(permit);
+    }
+
+    /// Authenticate the client by the certificate it presented, sparing it
+    /// the password
+    pub fn certified(&mut self, identity: Identity) {
+        Span::current().record("user", identity.user.as_str());
+        self.identity = Some(identity);
+    }
+
+    pub fn default(&mut self) -> &mut S {
         self.connection.default()
     }
 
+    /// Check the access rules for a request from this client
+    fn allowed(&mut self, dest: &Destination, resolved: &[IpAddr], cmd: Cmd) -> bool {
+        self.server.allowed(&Request {
+            client: self.peer.addr.ip(),
+            user: self.identity.as_ref(),
+            dest,
+            resolved,
+            cmd,
+        })
+    }
+
+    /// Pick the outbound for a request from this client, `None` meaning the
+    /// server's default
+    fn route(&mut self, dest: &Destination, resolved: &[IpAddr], cmd: Cmd) -> Option<Outbound> {
+        let route = self.server.route(&Request {
+            client: self.peer.addr.ip(),
+            user: self.identity.as_ref(),
+            dest,
+            resolved,
+            cmd,
+        });
+
+        let label = route_label(route.as_ref());
+        debug!(route = label, "routed");
+        Span::current().record("route", label);
+        self.access.route = Some(label.to_owned());
+
+        route
+    }
+
+    /// The metrics to count this client's relayed bytes in as they go
+    fn byte_counters(&self) -> ByteCounters {
+        let user = self.identity.as_ref().map_or("-", |id| id.user.as_str());
+        self.server.metrics().bytes(user)
+    }
+
     async fn send(&mut self, msg: &[u8]) -> Result<(), MyError> {
         self.connection.default().write_all(msg).await?;
         Ok(())
     }
 
-    pub async fn run_connection(&mut self, server: TcpStream) -> Result<(), MyError> {
+    /// Relay between the client and `server` until both sides are done,
+    /// counting bytes in `traffic` as they go and holding to the rate limits
+    pub async fn run_connection<R>(&mut self, server: R, traffic: &Traffic) -> Result<(), MyError>
+    where
+        R: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (up, down) = self
+            .server
+            .throttles(self.identity.as_ref(), self.peer.addr.ip());
+
+        let bytes = self.byte_counters();
+
         let (cr, cw) = self.connection.split();
         let (mut sr, mut sw) = split(server);
 
-        let client_to_server = async {
-            copy(cr, &mut sw).await?;
-            sw.shutdown().await
-        };
+        let result = tokio::try_join!(
+            pipe(cr, &mut sw, &up, |n| {
+                traffic.add_sent(n);
+                bytes.sent(n);
+            }),
+            pipe(&mut sr, cw, &down, |n| {
+                traffic.add_received(n);
+                bytes.received(n);
+            }),
+        );
+
+        self.access.sent = traffic.sent();
+        self.access.received = traffic.received();
+
+        result?;
+        Ok(())
+    }
 
-        let server_to_client = async {
-            copy(&mut sr, cw).await?;
-            cw.shutdown().await
+    /// Relay as a registered session until both sides are done, it is
+    /// terminated through the registry or a session timeout fires
+    async fn run_session<R>(&mut self, remote: R, session: Session) -> Result<(), MyError>
+    where
+        R: AsyncRead + AsyncWrite + Unpin,
+    {
+        let timeouts = self.server.timeouts;
+        self.access.remote = Some(session.remote2server);
+        self.server.session_start(session.clone());
+
+        let relayed = tokio::select! {
+            r = self.run_connection(remote, &session.traffic) => Ok(r),
+            ended = session_ended(&session, timeouts) => Err(ended),
         };
 
-        tokio::try_join!(client_to_server, server_to_client)?;
+        self.end_session(&session, relayed).await
+    }
 
-        Ok(())
+    /// Take `session` out of the registry once its relay has finished or
+    /// been cut short with an outcome and reason
+    async fn end_session(
+        &mut self,
+        session: &Session,
+        relayed: Result<Result<(), MyError>, (Outcome, &'static str)>,
+    ) -> Result<(), MyError> {
+        self.server.session_end(session);
+
+        match relayed {
+            Ok(result) => result,
+            Err((outcome, reason)) => {
+                info!(session = session.id, reason, "session closed");
+                self.access.sent = session.traffic.sent();
+                self.access.received = session.traffic.received();
+                self.access.outcome = Some(outcome);
+
+                // the remote side was closed when its relay was dropped
+                let _ = self.default().shutdown().await;
+                Ok(())
+            }
+        }
     }
 
-    pub async fn socks_init(&mut self) -> Result<SOCKSInit, MyError> {
-        match timeout(
-            Duration::from_secs(5),
-            self.connection.parser().parse(socks_init),
+    /// Connect to `dest` through `route`, failing with `TimedOut` after the
+    /// connect timeout so the client still gets a reply
+    async fn connect(
+        &self,
+        route: Option<&Outbound>,
+        dest: &Destination,
+        resolved: &[IpAddr],
+    ) -> io::Result<TcpStream> {
+        timeout(
+            self.server.timeouts.connect,
+            self.server.connect(route, dest, resolved),
         )
-        .await?
-        {
-            Ok(r) => Ok(r),
-            Err(e) => Err(e.into()),
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+    }
+
+    /// Wait for the remote host of a BIND to connect, up to the bind timeout
+    async fn bind_accept(&mut self, listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+        let accepted = timeout(self.server.timeouts.bind, listener.accept())
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+
+        if let Err(e) = &accepted {
+            info!(error = %e, "remote host didn't connect to bind");
+            self.access.outcome = Some(if e.kind() == ErrorKind::TimedOut {
+                Outcome::Timeout
+            } else {
+                Outcome::ConnectFailed
+            });
         }
+
+        accepted
+    }
+
+    /// Relay datagrams as a registered session until the controlling TCP
+    /// connection is closed, which ends the association, or the session is
+    /// terminated or times out.
+    pub async fn run_udp(&mut self, mut relay: UdpRelay, session: Session) -> Result<(), MyError> {
+        let timeouts = self.server.timeouts;
+        self.server.session_start(session.clone());
+
+        let control = self.connection.default();
+
+        let closed = async {
+            let mut buf = [0u8; 64];
+            loop {
+                match control.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        };
+
+        let relayed = tokio::select! {
+            _ = closed => Ok(Ok(())),
+            r = relay.run() => Ok(r),
+            ended = session_ended(&session, timeouts) => Err(ended),
+        };
+
+        self.access.sent = session.traffic.sent();
+        self.access.received = session.traffic.received();
+        self.end_session(&session, relayed).await
+    }
+
+    pub async fn socks_init(&mut self) -> Result<SOCKSInit, MyError> {
+        timeout(self.server.timeouts.init, self.connection.parse(socks_init)).await?
     }
 
     pub async fn socks5_auth_request(&mut self) -> Result<SOCKS5AuthRequest, MyError> {
-        match timeout(
-            Duration::from_secs(120),
-            self.connection.parser().parse(socks5_auth_request),
+        timeout(
+            self.server.timeouts.handshake,
+            self.connection.parse(socks5_auth_request),
         )
         .await?
-        {
-            Ok(r) => Ok(r),
-            Err(e) => Err(e.into()),
-        }
     }
 
     pub async fn socks5_connection_request(&mut self) -> Result<SOCKS5ConnectRequest, MyError> {
-        match timeout(
-            Duration::from_secs(120),
-            self.connection.parser().parse(socks5_connection_request),
+        timeout(
+            self.server.timeouts.handshake,
+            self.connection.parse(socks5_connection_request),
         )
         .await?
-        {
-            Ok(r) => Ok(r),
-            Err(e) => Err(e.into()),
-        }
     }
 
     pub async fn socks4_connect_reply(
@@ -171,27 +534,33 @@ impl Client {
         port: Option<u16>,
     ) -> Result<(), MyError> {
         let mut msg = BytesMut::with_capacity(8);
+        let code = if accepted { 0x5A } else { 0x5B };
+
+        self.server.metrics().reply("socks4", code.into());
+        self.end_handshake(accepted);
 
         msg.put_u8(0);
-        if accepted {
-            msg.put_u8(0x5A);
-        } else {
-            msg.put_u8(0x5B);
-        }
+        msg.put_u8(code);
 
-        if ip.is_some() {
-            let ip = ip.unwrap();
-            let port = port.unwrap();
-            msg.extend(ip.octets());
-            msg.put_u16(port);
-        } else {
-            msg.extend([0, 0, 0, 0, 0, 0]);
-        }
+        msg.put_u16(port.unwrap_or(0));
+        msg.extend(ip.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
 
         self.send(&msg).await
     }
 
+    /// Answer an HTTP request from the proxy itself
+    async fn http_reply(&mut self, status: u16) -> Result<(), MyError> {
+        self.server.metrics().reply("http", status);
+        self.end_handshake(status == 200);
+        self.send(&response(status)).await
+    }
+
     pub async fn socks5_auth_reply(&mut self, r: SOCKS5AuthReply) -> Result<(), MyError> {
+        if r == SOCKS5AuthReply::Denied {
+            self.access.outcome.get_or_insert(Outcome::AuthFailed);
+            self.end_handshake(false);
+        }
+
         let msg = [5u8, r as u8];
         self.send(msg.as_slice()).await
     }
@@ -202,124 +571,217 @@ impl Client {
         ip: Option<IpAddr>,
         port: Option<u16>,
     ) -> Result<(), MyError> {
-        let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
-        let port = port.unwrap_or(0);
-        match ip {
-            IpAddr::V4(ip) => {
-                let mut buf = BytesMut::with_capacity(10);
-                buf.extend([5u8, r as u8, 0, 1]);
-                buf.extend(ip.octets());
-                buf.put_u16(port);
-                self.send(&buf).await
-            }
-            IpAddr::V6(ip) => {
-                let mut buf = BytesMut::with_capacity(22);
-                buf.extend([5u8, r as u8, 0, 3]);
-                buf.extend(ip.octets());
-                buf.put_u16(port);
-                self.send(&buf).await
-            }
-        }
-    }
-
-    pub async fn handle_connection(&mut self, socks4: bool, socks5: bool) -> Result<(), MyError> {
-        match self.socks_init().await? {
-            SOCKSInit::V4(init) => {
-                if socks4 {
+        self.server.metrics().reply("socks5", r as u16);
+        self.end_handshake(r == SOCKS5ConnectReply::Accepted);
+
+        let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let bound = Destination::from(SocketAddr::new(ip, port.unwrap_or(0)));
+
+        let mut buf = BytesMut::with_capacity(22);
+        buf.extend([5u8, r as u8, 0]);
+        bound.put_socks5(&mut buf)?;
+        self.send(&buf).await
+    }
+
+    /// Serve the client until its connection ends. Connections that get as
+    /// far as a greeting in an enabled protocol are written to the access log.
+    pub async fn handle_connection(&mut self, protocols: Protocols) -> Result<(), MyError> {
+        let Some(first) = timeout(self.server.timeouts.init, self.connection.peek()).await?? else {
+            debug!("closed before sending anything");
+            return Ok(());
+        };
+
+        let protocol = match Protocol::detect(first) {
+            Some(protocol) if protocols.enabled(protocol) => protocol,
+            Some(protocol) => {
+                info!(%protocol, "protocol not enabled on this listener, closing");
+                return Ok(());
+            }
+            None => {
+                info!(first_byte = first, "unknown protocol, closing");
+                return Ok(());
+            }
+        };
+
+        let result = match protocol {
+            Protocol::Http => {
+                self.set_proto("http");
+                self.handle_http().await
+            }
+            _ => match self.socks_init().await? {
+                SOCKSInit::V4(init) => {
+                    self.set_proto("socks4");
                     self.handle_socks4(init).await
-                } else {
-                    Ok(())
                 }
-            }
-            SOCKSInit::V5(init) => {
-                if socks5 {
+                SOCKSInit::V5(init) => {
+                    self.set_proto("socks5");
                     self.handle_socks5(init).await
-                } else {
-                    Ok(())
                 }
-            }
+            },
+        };
+
+        if result.is_err() && self.access.outcome.is_none() {
+            self.access.outcome = Some(Outcome::Error);
+        }
+        self.access.user = self.identity.clone();
+        self.access.log();
+
+        // cut short before any reply
+        self.end_handshake(false);
+
+        result
+    }
+
+    /// Count the handshake once its final reply is sent. Failures take the
+    /// outcome already recorded, if any.
+    fn end_handshake(&mut self, accepted: bool) {
+        if self.handshake_done {
+            return;
         }
+        self.handshake_done = true;
+
+        let outcome = if accepted {
+            Outcome::Ok
+        } else {
+            self.access.outcome.unwrap_or(Outcome::Error)
+        };
+
+        self.server.metrics().handshake(
+            self.access.proto.unwrap_or("-"),
+            &outcome.to_string(),
+            self.access.started.elapsed(),
+        );
+    }
+
+    fn set_proto(&mut self, proto: &'static str) {
+        Span::current().record("proto", proto);
+        self.access.proto = Some(proto);
+    }
+
+    fn set_request(&mut self, cmd: Cmd, dest: &Destination) {
+        let span = Span::current();
+        span.record("cmd", tracing::field::display(cmd));
+        span.record("dest", tracing::field::display(dest));
+
+        debug!("request");
+
+        self.access.cmd = Some(cmd);
+        self.access.dest = Some(dest.clone());
+    }
+
+    fn connect_failed(&mut self, e: &io::Error) {
+        debug!(error = %e, "connect failed");
+
+        self.access.outcome = Some(if e.kind() == ErrorKind::PermissionDenied {
+            Outcome::Denied
+        } else {
+            Outcome::ConnectFailed
+        });
+    }
+
+    fn limit_reached(&mut self, scope: Scope) {
+        info!(%scope, "connection limit reached");
+        self.access.outcome = Some(Outcome::Limited);
     }
 
     async fn handle_socks4(&mut self, init: SOCKS4Init) -> Result<(), MyError> {
+        self.set_request(Cmd::from(&init.cmd), &init.dest);
+
+        // users are only known from a client certificate
+        if let Some(admission) = self.identity.as_ref().map(|id| self.server.admit_user(id)) {
+            match admission {
+                Ok(permit) => self.permits.push(permit),
+                Err(scope) => {
+                    self.limit_reached(scope);
+                    return self.socks4_connect_reply(false, None, None).await;
+                }
+            }
+        }
+
+        let resolved = match self.server.resolve_for_rules(&init.dest).await {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.connect_failed(&e);
+                self.socks4_connect_reply(false, None, None).await?;
+                return Err(e.into());
+            }
+        };
+
+        if !self.allowed(&init.dest, &resolved, Cmd::from(&init.cmd)) {
+            info!("denied by access rules");
+            self.access.outcome = Some(Outcome::Denied);
+            self.socks4_connect_reply(false, None, None).await?;
+            return Ok(());
+        }
+
         match init.cmd {
             SOCKS4Cmd::Connect => {
-                // apparently timeout is 2 mins for connection establishment
-                match timeout(
-                    Duration::from_secs(120),
-                    TcpStream::connect(String::from(&init.dest)),
-                )
-                .await?
-                {
+                let route = self.route(&init.dest, &resolved, Cmd::Connect);
+
+                match self.connect(route.as_ref(), &init.dest, &resolved).await {
                     Ok(forward) => {
+                        let session = Session::new(
+                            self.peer,
+                            forward.peer()?,
+                            init.dest,
+                            self.identity.clone(),
+                            route,
+                        );
+
                         // connection accepted
                         self.socks4_connect_reply(true, None, None).await?;
-                        self.run_connection(forward).await?;
-
-                        Ok(())
+                        self.run_session(forward, session).await
                     }
                     Err(e) => {
-                        // connection failed
+                        self.connect_failed(&e);
                         self.socks4_connect_reply(false, None, None).await?;
                         Err(e.into())
                     }
                 }
             }
             SOCKS4Cmd::Bind => {
-                let addr_info = {
-                    let mut receiver = self.sender.subscribe();
-
-                    let orig_dest = Arc::new(init.dest);
-
-                    self.sender
-                        .send(Message::Request(orig_dest.clone()))
-                        .unwrap();
-
-                    loop {
-                        if let Ok(Message::Reply(dest, session)) = receiver.recv().await {
-                            if dest == orig_dest {
-                                break session;
-                            }
-                        }
-                    }
-                };
+                let addr_info = self.server.find_session(&init.dest);
 
                 if addr_info.is_none() {
                     self.socks4_connect_reply(false, None, None).await?;
                     return Ok(());
                 }
 
-                let addr_info = addr_info.unwrap();
+                // SOCKS4 replies only hold an IPv4 address. For an IPv6 session
+                // listen on every address and reply 0.0.0.0, which tells the
+                // client to use the address of the proxy.
+                let (bind_ip, reply_ip) = match addr_info.unwrap().server2remote.ip() {
+                    IpAddr::V4(ip) => (IpAddr::V4(ip), Some(ip)),
+                    IpAddr::V6(_) => (IpAddr::V6(Ipv6Addr::UNSPECIFIED), None),
+                };
 
-                match addr_info.server2remote.ip() {
-                    IpAddr::V4(ip) => {
-                        match TcpListener::bind(SocketAddr::new(ip.into(), 0)).await {
-                            Ok(listener) => {
-                                let listen_addr = listener.local_addr().unwrap();
+                match TcpListener::bind(SocketAddr::new(bind_ip, 0)).await {
+                    Ok(listener) => {
+                        let listen_addr = listener.local_addr().unwrap();
 
-                                self.socks4_connect_reply(true, Some(ip), Some(listen_addr.port()))
-                                    .await?;
+                        self.socks4_connect_reply(true, reply_ip, Some(listen_addr.port()))
+                            .await?;
 
-                                match listener.accept().await {
-                                    Ok((stream, _)) => {
-                                        self.socks4_connect_reply(true, None, None).await?;
+                        match self.bind_accept(&listener).await {
+                            Ok((stream, _)) => {
+                                self.socks4_connect_reply(true, None, None).await?;
 
-                                        self.run_connection(stream).await?;
-                                    }
-                                    Err(_) => {
-                                        self.socks4_connect_reply(false, None, None).await?;
-                                    }
-                                }
+                                let session = Session::new(
+                                    self.peer,
+                                    stream.peer()?,
+                                    init.dest.clone(),
+                                    self.identity.clone(),
+                                    None,
+                                );
+                                self.run_session(stream, session).await?;
                             }
                             Err(_) => {
                                 self.socks4_connect_reply(false, None, None).await?;
                             }
                         }
                     }
-                    IpAddr::V6(_) => {
-                        // only support ipv4
+                    Err(_) => {
                         self.socks4_connect_reply(false, None, None).await?;
-                        return Ok(());
                     }
                 }
 
@@ -329,31 +791,18 @@ impl Client {
     }
 
     async fn handle_socks5(&mut self, init: SOCKS5Init) -> Result<(), MyError> {
-        let auth_method = {
-            let auth_methods = Arc::new(init.auth_methods);
-
-            let mut receiver = self.sender.subscribe();
-
-            self.sender
-                .send(Message::AuthMethodReq(auth_methods.clone()))
-                .unwrap();
-
-            let reply = {
-                loop {
-                    if let Ok(Message::AuthMethodReply(methods, selected)) = receiver.recv().await {
-                        if methods == auth_methods {
-                            break selected;
-                        }
-                    }
-                }
-            };
+        // only a client certificate authenticates before the handshake
+        let certified = self.identity.is_some();
 
-            if reply.is_none() {
+        let auth_method = match self
+            .server
+            .select_auth_method(&init.auth_methods, certified)
+        {
+            Some(method) => method,
+            None => {
                 self.socks5_auth_reply(SOCKS5AuthReply::Denied).await?;
                 return Ok(());
             }
-
-            reply.unwrap()
         };
 
         match auth_method {
@@ -365,49 +814,93 @@ impl Client {
 
                 let client_auth = self.socks5_auth_request().await?;
 
+                // RFC 1929 only defines version 1 of the subnegotiation
+                if client_auth.ver != 1 {
+                    self.socks5_auth_reply(SOCKS5AuthReply::Denied).await?;
+                    return Ok(());
+                }
+
                 if let (Ok(user), Ok(pass)) = (
                     String::from_utf8(client_auth.id),
                     String::from_utf8(client_auth.pw),
                 ) {
-                    let user = Arc::new(User { user, pass });
-
-                    let mut receiver = self.sender.subscribe();
-                    self.sender.send(Message::AuthRequst(user.clone())).unwrap();
-
-                    loop {
-                        if let Ok(Message::AuthReply(req, accepted)) = receiver.recv().await {
-                            if req == user {
-                                if accepted {
-                                    self.socks5_auth_reply(SOCKS5AuthReply::Accepted).await?;
-                                    break;
-                                }
-                                self.socks5_auth_reply(SOCKS5AuthReply::Denied).await?;
-                                return Ok(());
-                            }
+                    match self.server.authenticate(&user, &pass).await {
+                        Ok(identity) => {
+                            Span::current().record("user", identity.user.as_str());
+                            self.identity = Some(identity);
+                            self.socks5_auth_reply(SOCKS5AuthReply::Accepted).await?;
+                        }
+                        Err(e) => {
+                            info!(user, reason = %e, "authentication failed");
+                            self.server.metrics().auth_failure();
+                            self.socks5_auth_reply(SOCKS5AuthReply::Denied).await?;
+                            return Ok(());
                         }
                     }
                 } else {
+                    info!("authentication failed, credentials aren't UTF-8");
+                    self.server.metrics().auth_failure();
                     self.socks5_auth_reply(SOCKS5AuthReply::Denied).await?;
+                    return Ok(());
                 }
             }
         };
 
         let req = self.socks5_connection_request().await?;
+        self.set_request(Cmd::from(&req.cmd), &req.dest);
 
-        return match req.cmd {
+        if let Some(admission) = self.identity.as_ref().map(|id| self.server.admit_user(id)) {
+            match admission {
+                Ok(permit) => self.permits.push(permit),
+                Err(scope) => {
+                    self.limit_reached(scope);
+                    self.socks5_connection_reply(SOCKS5ConnectReply::Failure, None, None)
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut resolved = Vec::new();
+        let allowed = match req.cmd {
+            // datagrams are checked one by one as they are relayed
+            SOCKS5Cmd::Udp => {
+                !self
+                    .server
+                    .acl()
+                    .denies_all(self.peer.addr.ip(), self.identity.as_ref(), Cmd::Udp)
+            }
+            _ => match self.server.resolve_for_rules(&req.dest).await {
+                Ok(addrs) => {
+                    resolved = addrs;
+                    self.allowed(&req.dest, &resolved, Cmd::from(&req.cmd))
+                }
+                Err(e) => {
+                    self.connect_failed(&e);
+                    self.socks5_connection_reply(SOCKS5ConnectReply::HostUnreachable, None, None)
+                        .await?;
+                    return Err(e.into());
+                }
+            },
+        };
+
+        if !allowed {
+            info!("denied by access rules");
+            self.access.outcome = Some(Outcome::Denied);
+            self.socks5_connection_reply(SOCKS5ConnectReply::NotAllowed, None, None)
+                .await?;
+            return Ok(());
+        }
+
+        match req.cmd {
             SOCKS5Cmd::Connect => {
-                match timeout(
-                    Duration::from_secs(120),
-                    TcpStream::connect(String::from(&req.dest)),
-                )
-                .await?
-                {
-                    Ok(server) => {
-                        let msg = Session::new(self.default(), &server, req.dest);
+                let route = self.route(&req.dest, &resolved, Cmd::Connect);
 
-                        self.sender
-                            .send(Message::SessionStart(msg.clone()))
-                            .unwrap();
+                match self.connect(route.as_ref(), &req.dest, &resolved).await {
+                    Ok(server) => {
+                        let identity = self.identity.clone();
+                        let session =
+                            Session::new(self.peer, server.peer()?, req.dest, identity, route);
 
                         let socket_addr = server.local_addr()?;
                         self.socks5_connection_reply(
@@ -417,19 +910,17 @@ impl Client {
                         )
                         .await?;
 
-                        self.run_connection(server).await?;
-
-                        self.sender.send(Message::SessionEnd(msg)).unwrap();
-                        Ok(())
+                        self.run_session(server, session).await
                     }
                     Err(e) => {
-                        // should match on err.kind() instead
+                        self.connect_failed(&e);
 
                         let reply = match e.kind() {
                             ErrorKind::ConnectionRefused => SOCKS5ConnectReply::ConnectionRefused,
                             ErrorKind::HostUnreachable => SOCKS5ConnectReply::HostUnreachable,
                             ErrorKind::NetworkUnreachable => SOCKS5ConnectReply::NetworkUnreachable,
-
+                            ErrorKind::PermissionDenied => SOCKS5ConnectReply::NotAllowed,
+                            ErrorKind::TimedOut => SOCKS5ConnectReply::TTLExpired,
                             _ => SOCKS5ConnectReply::Failure,
                         };
 
@@ -440,22 +931,7 @@ impl Client {
                 }
             }
             SOCKS5Cmd::Bind => {
-                let addr_info = {
-                    let mut receiver = self.sender.subscribe();
-
-                    let orig_dest = Arc::new(req.dest);
-                    self.sender
-                        .send(Message::Request(orig_dest.clone()))
-                        .unwrap();
-
-                    loop {
-                        if let Ok(Message::Reply(dest, session)) = receiver.recv().await {
-                            if dest == orig_dest {
-                                break session;
-                            }
-                        }
-                    }
-                };
+                let addr_info = self.server.find_session(&req.dest);
 
                 if addr_info.is_none() {
                     self.socks5_connection_reply(SOCKS5ConnectReply::Failure, None, None)
@@ -479,7 +955,7 @@ impl Client {
                         )
                         .await?;
 
-                        match listener.accept().await {
+                        match self.bind_accept(&listener).await {
                             Ok((stream, socket)) => {
                                 self.socks5_connection_reply(
                                     SOCKS5ConnectReply::Accepted,
@@ -488,7 +964,15 @@ impl Client {
                                 )
                                 .await?;
 
-                                self.run_connection(stream).await?;
+                                let identity = self.identity.clone();
+                                let session = Session::new(
+                                    self.peer,
+                                    stream.peer()?,
+                                    req.dest.clone(),
+                                    identity,
+                                    None,
+                                );
+                                self.run_session(stream, session).await?;
                             }
                             Err(_) => {
                                 self.socks5_connection_reply(
@@ -509,10 +993,870 @@ impl Client {
                 Ok(())
             }
             SOCKS5Cmd::Udp => {
-                self.socks5_connection_reply(SOCKS5ConnectReply::CommandNotSupported, None, None)
-                    .await?;
-                Ok(())
+                match UdpRelay::bind(
+                    self.peer.local.ip(),
+                    self.peer.addr,
+                    &req.dest,
+                    self.server.clone(),
+                    self.identity.clone(),
+                )
+                .await
+                {
+                    Ok(relay) => {
+                        let relay_addr = relay.local_addr()?;
+                        let relay_end = Peer {
+                            addr: relay_addr,
+                            local: relay_addr,
+                        };
+                        let session = Session::new(
+                            self.peer,
+                            relay_end,
+                            req.dest,
+                            self.identity.clone(),
+                            None,
+                        )
+                        .counted_in(relay.traffic().clone());
+
+                        self.socks5_connection_reply(
+                            SOCKS5ConnectReply::Accepted,
+                            Some(relay_addr.ip()),
+                            Some(relay_addr.port()),
+                        )
+                        .await?;
+
+                        self.run_udp(relay, session).await
+                    }
+                    Err(e) => {
+                        self.socks5_connection_reply(SOCKS5ConnectReply::Failure, None, None)
+                            .await?;
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serve one HTTP proxy request: a CONNECT tunnel, or a plain HTTP
+    /// request forwarded to the origin server, after which the connection
+    /// is closed
+    async fn handle_http(&mut self) -> Result<(), MyError> {
+        let head = timeout(
+            self.server.timeouts.init,
+            read_head(self.connection.parser()),
+        )
+        .await??;
+
+        let parsed = HttpRequest::parse(&head).and_then(|req| {
+            let body = if req.is_connect() {
+                RequestBody::Empty
+            } else {
+                req.body()?
+            };
+            Ok((req.destination()?, body, req))
+        });
+        let (dest, body, req) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                debug!("bad HTTP proxy request");
+                self.http_reply(400).await?;
+                return Err(e);
+            }
+        };
+        self.set_request(Cmd::Connect, &dest);
+
+        if self.server.auth_required() && self.identity.is_none() {
+            let Some(credentials) = req.credentials() else {
+                debug!("no proxy credentials");
+                self.access.outcome = Some(Outcome::AuthFailed);
+                return self.http_reply(407).await;
+            };
+
+            match self
+                .server
+                .authenticate(&credentials.user, &credentials.pass)
+                .await
+            {
+                Ok(identity) => {
+                    Span::current().record("user", identity.user.as_str());
+                    self.identity = Some(identity);
+                }
+                Err(e) => {
+                    info!(user = credentials.user, reason = %e, "authentication failed");
+                    self.server.metrics().auth_failure();
+                    self.access.outcome = Some(Outcome::AuthFailed);
+                    return self.http_reply(407).await;
+                }
+            }
+        }
+
+        if let Some(admission) = self.identity.as_ref().map(|id| self.server.admit_user(id)) {
+            match admission {
+                Ok(permit) => self.permits.push(permit),
+                Err(scope) => {
+                    self.limit_reached(scope);
+                    return self.http_reply(503).await;
+                }
+            }
+        }
+
+        let resolved = match self.server.resolve_for_rules(&dest).await {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.connect_failed(&e);
+                self.http_reply(502).await?;
+                return Err(e.into());
+            }
+        };
+
+        if !self.allowed(&dest, &resolved, Cmd::Connect) {
+            info!("denied by access rules");
+            self.access.outcome = Some(Outcome::Denied);
+            return self.http_reply(403).await;
+        }
+
+        let route = self.route(&dest, &resolved, Cmd::Connect);
+
+        let mut server = match self.connect(route.as_ref(), &dest, &resolved).await {
+            Ok(server) => server,
+            Err(e) => {
+                self.connect_failed(&e);
+
+                let status = match e.kind() {
+                    ErrorKind::PermissionDenied => 403,
+                    ErrorKind::TimedOut => 504,
+                    _ => 502,
+                };
+                self.http_reply(status).await?;
+
+                return Err(e.into());
+            }
+        };
+
+        let identity = self.identity.clone();
+        let session = Session::new(self.peer, server.peer()?, dest, identity, route);
+
+        if !req.is_connect() {
+            self.end_handshake(true);
+            return self
+                .run_forward(server, session, req.forward_head(), body)
+                .await;
+        }
+
+        self.http_reply(200).await?;
+
+        // a tunnel payload the client sent along with its request
+        let early = self.connection.take_buffered();
+        server.write_all(&early).await?;
+        session.traffic.add_sent(early.len() as u64);
+        self.byte_counters().sent(early.len() as u64);
+
+        self.run_session(server, session).await
+    }
+
+    /// Relay a forwarded request as a registered session: its head and body
+    /// to the origin server, then the response back until the server closes.
+    /// Nothing the client sends after the body is read, so a pipelined
+    /// request never reaches the origin without its own checks.
+    async fn run_forward(
+        &mut self,
+        server: TcpStream,
+        session: Session,
+        head: Vec<u8>,
+        body: RequestBody,
+    ) -> Result<(), MyError> {
+        let timeouts = self.server.timeouts;
+        self.access.remote = Some(session.remote2server);
+        self.server.session_start(session.clone());
+
+        let relayed = tokio::select! {
+            r = self.forward(server, &session.traffic, &head, body) => Ok(r),
+            ended = session_ended(&session, timeouts) => Err(ended),
+        };
+
+        self.end_session(&session, relayed).await
+    }
+
+    async fn forward(
+        &mut self,
+        mut server: TcpStream,
+        traffic: &Traffic,
+        head: &[u8],
+        body: RequestBody,
+    ) -> Result<(), MyError> {
+        let (up, down) = self
+            .server
+            .throttles(self.identity.as_ref(), self.peer.addr.ip());
+        let bytes = self.byte_counters();
+        let sent = |n| {
+            traffic.add_sent(n);
+            bytes.sent(n);
+        };
+
+        up.take(head.len() as u64).await;
+        server.write_all(head).await?;
+        sent(head.len() as u64);
+
+        let copied = copy_body(self.connection.parser(), &mut server, body, &up, sent).await;
+
+        if copied.is_ok() && !self.connection.take_buffered().is_empty() {
+            debug!("ignoring what the client sent after its request");
+        }
+
+        let result = match copied {
+            Ok(()) => {
+                let (_, cw) = self.connection.split();
+                pipe(&mut server, cw, &down, |n| {
+                    traffic.add_received(n);
+                    bytes.received(n);
+                })
+                .await
+                .map_err(MyError::from)
+            }
+            Err(e) => Err(e),
+        };
+
+        self.access.sent = traffic.sent();
+        self.access.received = traffic.received();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{Acl, Action};
+    use crate::auth::StaticUsers;
+    use crate::connector::{Chain, Direct};
+    use crate::resolver::Resolver;
+    use crate::router::Router;
+    use crate::server::ServerConfig;
+    use async_trait::async_trait;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+    use tokio::time::Instant;
+
+    fn peer() -> Peer {
+        Peer {
+            addr: "127.0.0.1:40000".parse().unwrap(),
+            local: "127.0.0.1:1080".parse().unwrap(),
+        }
+    }
+
+    /// Serve one client over an in-memory stream, returning the client's end
+    fn serve(config: ServerConfig, protocols: Protocols) -> DuplexStream {
+        let (ours, theirs) = duplex(4096);
+        let server = Arc::new(Server::new(config, peer().local));
+
+        tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), server);
+            let _ = client.handle_connection(protocols).await;
+        });
+
+        ours
+    }
+
+    fn socks5() -> Protocols {
+        Protocols {
+            socks5: true,
+            ..Protocols::default()
+        }
+    }
+
+    fn with_users() -> ServerConfig {
+        let users = StaticUsers::new(vec!["alice:secret".parse().unwrap()]);
+
+        ServerConfig {
+            authenticator: Some(Arc::new(users)),
+            ..ServerConfig::default()
+        }
+    }
+
+    /// The reply to a username/password subnegotiation of version `ver`
+    async fn userpass_reply(ver: u8) -> [u8; 2] {
+        let mut s = serve(with_users(), socks5());
+
+        s.write_all(&[5, 1, 2]).await.unwrap();
+        let mut reply = [0u8; 2];
+        s.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2]);
+
+        s.write_all(&[ver, 5]).await.unwrap();
+        s.write_all(b"alice").await.unwrap();
+        s.write_all(&[6]).await.unwrap();
+        s.write_all(b"secret").await.unwrap();
+        s.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn messages_may_arrive_a_byte_at_a_time() {
+        let mut s = serve(ServerConfig::default(), socks5());
+
+        for byte in [5, 2, 0, 2] {
+            s.write_all(&[byte]).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let mut reply = [0u8; 2];
+        s.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn closing_partway_through_a_message_ends_the_handshake() {
+        let (mut s, theirs) = duplex(4096);
+        let server = Arc::new(Server::new(ServerConfig::default(), peer().local));
+        let client = tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), server);
+            client.handle_connection(socks5()).await
+        });
+
+        s.write_all(&[5, 2, 0]).await.unwrap();
+        drop(s);
+
+        let ended = timeout(Duration::from_secs(5), client).await.unwrap();
+        assert!(ended.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn userpass_version_1_is_accepted() {
+        assert_eq!(userpass_reply(1).await, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn userpass_other_versions_are_denied() {
+        assert_eq!(userpass_reply(2).await, [5, 255]);
+    }
+
+    #[tokio::test]
+    async fn socks4_replies_put_the_port_before_the_address() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let server = Arc::new(Server::new(ServerConfig::default(), peer().local));
+
+        let socks4 = |cmd: u8| {
+            let (ours, theirs) = duplex(4096);
+            let server = server.clone();
+            tokio::spawn(async move {
+                let mut client = Client::new(theirs, peer(), server);
+                let protocols = Protocols {
+                    socks4: true,
+                    ..Protocols::default()
+                };
+                let _ = client.handle_connection(protocols).await;
+            });
+
+            let mut request = vec![4, cmd];
+            request.extend(port.to_be_bytes());
+            request.extend([127, 0, 0, 1, 0]);
+            (ours, request)
+        };
+
+        // a BIND needs the session it belongs to
+        let (mut connect, request) = socks4(1);
+        connect.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 8];
+        connect.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x5A);
+
+        let (mut bind, request) = socks4(2);
+        bind.write_all(&request).await.unwrap();
+        bind.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0, 0x5A]);
+        assert_eq!(reply[4..], [127, 0, 0, 1]);
+
+        let listening = u16::from_be_bytes([reply[2], reply[3]]);
+        assert!(TcpStream::connect(("127.0.0.1", listening)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn socks4_sessions_carry_the_certificate_user() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let server = Arc::new(Server::new(ServerConfig::default(), peer().local));
+
+        let (mut s, theirs) = duplex(4096);
+        let serving = server.clone();
+        tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), serving);
+            client.certified(Identity::new("alice"));
+            let protocols = Protocols {
+                socks4: true,
+                ..Protocols::default()
+            };
+            let _ = client.handle_connection(protocols).await;
+        });
+
+        let mut request = vec![4, 1];
+        request.extend(port.to_be_bytes());
+        request.extend([127, 0, 0, 1, 0]);
+        s.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 8];
+        s.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x5A);
+
+        // registered right after the reply
+        let mut sessions = server.active_sessions();
+        for _ in 0..100 {
+            if !sessions.is_empty() {
+                break;
+            }
+            tokio::task::yield_now().await;
+            sessions = server.active_sessions();
+        }
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].user, Some(Identity::new("alice")));
+    }
+
+    /// Relay through a client served over `stream` to an echo server
+    async fn echo_through<S>(mut s: S, server: Arc<Server>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = target.local_addr().unwrap() else {
+            unreachable!()
+        };
+        tokio::spawn(async move {
+            let (mut remote, _) = target.accept().await.unwrap();
+            let (mut r, mut w) = remote.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+
+        s.write_all(&[5, 1, 0]).await.unwrap();
+        s.read_exact(&mut [0u8; 2]).await.unwrap();
+
+        let mut request = vec![5, 1, 0, 1];
+        request.extend(addr.ip().octets());
+        request.extend(addr.port().to_be_bytes());
+        s.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        s.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        s.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        s.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let sessions = server.active_sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].traffic.sent(), 4);
+    }
+
+    #[tokio::test]
+    async fn clients_are_served_over_in_memory_streams() {
+        let (ours, theirs) = duplex(4096);
+        let server = Arc::new(Server::new(ServerConfig::default(), peer().local));
+
+        let serving = server.clone();
+        tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), serving);
+            let _ = client.handle_connection(socks5()).await;
+        });
+
+        echo_through(ours, server).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn clients_are_served_over_unix_sockets() {
+        let (ours, theirs) = tokio::net::UnixStream::pair().unwrap();
+        let peer = theirs.peer().unwrap();
+        assert!(peer.addr.ip().is_loopback());
+
+        let server = Arc::new(Server::new(ServerConfig::default(), peer.local));
+        let serving = server.clone();
+        tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer, serving);
+            let _ = client.handle_connection(socks5()).await;
+        });
+
+        echo_through(ours, server).await;
+    }
+
+    /// The reply to a SOCKS5 CONNECT from a Unix socket client under `rule`
+    #[cfg(unix)]
+    async fn unix_connect_reply(rule: &str, default: Action) -> u8 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+
+        let (mut s, theirs) = tokio::net::UnixStream::pair().unwrap();
+        let peer = theirs.peer().unwrap();
+        let config = ServerConfig {
+            acl: Acl::new(vec![rule.parse().unwrap()], default),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config, peer.local));
+        tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer, server);
+            let _ = client.handle_connection(socks5()).await;
+        });
+
+        s.write_all(&[5, 1, 0]).await.unwrap();
+        s.read_exact(&mut [0u8; 2]).await.unwrap();
+        s.write_all(&[5, 1, 0, 1]).await.unwrap();
+        s.write_all(&addr.ip().octets()).await.unwrap();
+        s.write_all(&addr.port().to_be_bytes()).await.unwrap();
+        let mut reply = [0u8; 10];
+        s.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_clients_are_matched_as_loopback() {
+        let reply = unix_connect_reply("deny client=127.0.0.1", Action::Allow).await;
+        assert_eq!(reply, SOCKS5ConnectReply::NotAllowed as u8);
+
+        let reply = unix_connect_reply("allow client=127.0.0.0/8", Action::Deny).await;
+        assert_eq!(reply, SOCKS5ConnectReply::Accepted as u8);
+
+        let reply = unix_connect_reply("allow client=10.0.0.0/8", Action::Deny).await;
+        assert_eq!(reply, SOCKS5ConnectReply::NotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn udp_associations_end_with_their_control_connection() {
+        let (mut s, theirs) = duplex(4096);
+        let server = Arc::new(Server::new(ServerConfig::default(), peer().local));
+
+        let serving = server.clone();
+        let client = tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), serving);
+            let _ = client.handle_connection(socks5()).await;
+        });
+
+        s.write_all(&[5, 1, 0]).await.unwrap();
+        s.read_exact(&mut [0u8; 2]).await.unwrap();
+        s.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 10];
+        s.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        drop(s);
+        timeout(Duration::from_secs(5), client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(server.active_sessions().is_empty());
+    }
+
+    /// Relay a session between in-memory client and remote ends under
+    /// `timeouts`, returning both ends and the relay's outcome once it ends
+    fn relay_session(
+        timeouts: Timeouts,
+    ) -> (DuplexStream, DuplexStream, JoinHandle<Option<Outcome>>) {
+        let (client_end, theirs) = duplex(4096);
+        let (remote_end, remote) = duplex(4096);
+        let config = ServerConfig {
+            timeouts,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config, peer().local));
+
+        let relay = tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), server);
+            let dest: Destination = "example.com:80".parse().unwrap();
+            let session = Session::new(peer(), peer(), dest, None, None);
+            client.run_session(remote, session).await.unwrap();
+            client.access.outcome
+        });
+
+        (client_end, remote_end, relay)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_sessions_are_closed() {
+        let (mut client, mut remote, relay) = relay_session(Timeouts {
+            idle: Some(Duration::from_secs(30)),
+            ..Timeouts::default()
+        });
+        let started = Instant::now();
+
+        // activity keeps the session open past the idle timeout
+        sleep(Duration::from_secs(20)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        sleep(Duration::from_secs(20)).await;
+        assert!(!relay.is_finished());
+
+        assert_eq!(relay.await.unwrap(), Some(Outcome::Timeout));
+        let closed = started.elapsed();
+        assert!(closed >= Duration::from_secs(50) && closed < Duration::from_secs(51));
+
+        // the client's end is closed along with the session
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_are_cut_at_their_max_duration() {
+        let (mut client, mut remote, relay) = relay_session(Timeouts {
+            idle: Some(Duration::from_secs(30)),
+            max_session: Some(Duration::from_secs(60)),
+            ..Timeouts::default()
+        });
+        let started = Instant::now();
+
+        // busy all along, the session still ends on time
+        let busy = tokio::spawn(async move {
+            let mut buf = [0u8; 4];
+            while client.write_all(b"ping").await.is_ok() {
+                if remote.read_exact(&mut buf).await.is_err() {
+                    break;
+                }
+                sleep(Duration::from_secs(10)).await;
+            }
+        });
+
+        assert_eq!(relay.await.unwrap(), Some(Outcome::Timeout));
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        busy.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_without_timeouts_stay_open() {
+        let (_client, _remote, relay) = relay_session(Timeouts::default());
+
+        sleep(Duration::from_secs(24 * 60 * 60)).await;
+        assert!(!relay.is_finished());
+    }
+
+    /// What the origin receives for a forwarded request written as
+    /// `request`, with `{}` standing for the origin's address, and what the
+    /// client gets back
+    async fn forwarded(request: &str) -> (String, String) {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = request.replace("{}", &origin.local_addr().unwrap().to_string());
+        let http = Protocols {
+            http: true,
+            ..Protocols::default()
+        };
+        let mut s = serve(ServerConfig::default(), http);
+        s.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut conn, _) = origin.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok(Ok(n)) = timeout(Duration::from_millis(300), conn.read(&mut buf)).await {
+            if n == 0 {
+                break;
+            }
+            received.extend(&buf[..n]);
+        }
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        drop(conn);
+
+        let mut response = Vec::new();
+        s.read_to_end(&mut response).await.unwrap();
+        (
+            String::from_utf8(received).unwrap(),
+            String::from_utf8(response).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_not_forwarded() {
+        let (received, response) = forwarded(
+            "POST http://{}/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+             GET http://{}/b HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+        )
+        .await;
+
+        assert!(received.starts_with("POST /a HTTP/1.1\r\n"), "{}", received);
+        assert!(received.ends_with("\r\n\r\nhello"), "{}", received);
+        assert!(!received.contains("/b"));
+        assert!(!received.contains("YWxpY2U6c2VjcmV0"));
+        assert!(response.ends_with("\r\n\r\nok"));
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_forwarded_to_their_end() {
+        let (received, _) = forwarded(
+            "POST http://{}/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;ext=1\r\nhello\r\n0\r\nTrailer: x\r\n\r\n\
+             GET http://{}/b HTTP/1.1\r\n\r\n",
+        )
+        .await;
+
+        assert!(
+            received.ends_with("\r\n\r\n5;ext=1\r\nhello\r\n0\r\nTrailer: x\r\n\r\n"),
+            "{}",
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn ambiguous_bodies_are_refused() {
+        let http = Protocols {
+            http: true,
+            ..Protocols::default()
+        };
+        let mut s = serve(ServerConfig::default(), http);
+        s.write_all(
+            b"POST http://127.0.0.1:9/ HTTP/1.1\r\nContent-Length: 5\r\n\
+              Transfer-Encoding: chunked\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+        let mut response = Vec::new();
+        s.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400 "));
+    }
+
+    /// Knows `app.test` as loopback, which the system resolver doesn't
+    #[derive(Debug)]
+    struct TestNames;
+
+    #[async_trait]
+    impl Resolver for TestNames {
+        async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+            match host {
+                "app.test" => Ok(vec![IpAddr::from([127, 0, 0, 1])]),
+                _ => Err(ErrorKind::TimedOut.into()),
             }
+        }
+    }
+
+    /// The SOCKS5 reply code to a CONNECT to `name`
+    async fn connect_reply(config: ServerConfig, name: &str, port: u16) -> u8 {
+        let mut s = serve(config, socks5());
+
+        s.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        s.read_exact(&mut reply).await.unwrap();
+
+        s.write_all(&[5, 1, 0, 3, name.len() as u8]).await.unwrap();
+        s.write_all(name.as_bytes()).await.unwrap();
+        s.write_all(&port.to_be_bytes()).await.unwrap();
+
+        let mut reply = [0u8; 4];
+        s.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    /// The route a SOCKS5 CONNECT to `127.0.0.1:port` was logged with
+    async fn logged_route(routes: &[&str], port: u16) -> Option<String> {
+        let (mut s, theirs) = duplex(4096);
+        let config = ServerConfig {
+            router: Router::new(
+                routes.iter().map(|r| r.parse().unwrap()).collect(),
+                vec![],
+                Direct::default(),
+            )
+            .unwrap(),
+            ..ServerConfig::default()
         };
+        let server = Arc::new(Server::new(config, peer().local));
+        let client = tokio::spawn(async move {
+            let mut client = Client::new(theirs, peer(), server);
+            let _ = client.handle_connection(socks5()).await;
+            client.access.route
+        });
+
+        s.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        s.read_exact(&mut reply).await.unwrap();
+        s.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).await.unwrap();
+        s.write_all(&port.to_be_bytes()).await.unwrap();
+        let mut reply = [0u8; 10];
+        s.read_exact(&mut reply).await.unwrap();
+        drop(s);
+
+        client.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_route_taken_is_logged() {
+        let route = logged_route(&["reject port=9"], 9).await;
+        assert_eq!(route.as_deref(), Some("reject"));
+
+        // the destination hangs up, ending the relays
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((remote, _)) = listener.accept().await {
+                drop(remote);
+            }
+        });
+
+        let route = logged_route(&["direct port=8-9"], port).await;
+        assert_eq!(route.as_deref(), Some("default"));
+        let route = logged_route(&[&format!("direct port={}", port)], port).await;
+        assert_eq!(route.as_deref(), Some("direct"));
+    }
+
+    /// A server whose connections go through a fake upstream, which answers
+    /// its greeting and then each request with the next of `replies`
+    async fn through_upstream(scheme: &str, replies: Vec<&'static [u8]>) -> ServerConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut proxy, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            for reply in replies {
+                let _ = proxy.read(&mut buf).await.unwrap();
+                proxy.write_all(reply).await.unwrap();
+            }
+        });
+
+        let hop = format!("{}://{}", scheme, addr).parse().unwrap();
+        ServerConfig {
+            connector: Arc::new(Chain::new(vec![hop])),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_failures_are_passed_on_to_the_client() {
+        let config =
+            through_upstream("socks5", vec![&[5, 0], &[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]]).await;
+        let reply = connect_reply(config, "example.com", 80).await;
+        assert_eq!(reply, SOCKS5ConnectReply::ConnectionRefused as u8);
+
+        let config = through_upstream("http", vec![b"HTTP/1.1 407 Who\r\n\r\n"]).await;
+        let reply = connect_reply(config, "example.com", 80).await;
+        assert_eq!(reply, SOCKS5ConnectReply::NotAllowed as u8);
+
+        let config = through_upstream("socks4a", vec![&[0, 0x5B, 0, 0, 0, 0, 0, 0]]).await;
+        let reply = connect_reply(config, "example.com", 80).await;
+        assert_eq!(reply, SOCKS5ConnectReply::ConnectionRefused as u8);
+    }
+
+    fn with_address_rule(rule: &str) -> ServerConfig {
+        ServerConfig {
+            acl: Acl::new(vec![rule.parse().unwrap()], Action::Allow),
+            resolver: Arc::new(TestNames),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_lookup_for_address_rules_is_host_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // the rule can't be checked, though the system resolver could find
+        // the destination
+        let config = with_address_rule("deny dst=127.0.0.0/8");
+        let reply = connect_reply(config, "localhost", port).await;
+        assert_eq!(reply, SOCKS5ConnectReply::HostUnreachable as u8);
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_addresses_the_rules_checked() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // the default connector's system resolver can't look up app.test
+        let config = with_address_rule("deny dst=169.254.0.0/16");
+        let reply = connect_reply(config, "app.test", port).await;
+        assert_eq!(reply, SOCKS5ConnectReply::Accepted as u8);
     }
 }
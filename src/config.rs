@@ -0,0 +1,1196 @@
+use crate::acl::{Acl, Action};
+use crate::admin::AdminAddr;
+use crate::auth::{CredentialsFile, StaticUsers};
+use crate::connector::{Chain, Direct, Upstream};
+use crate::connlimit::{ConnLimiter, ConnLimits};
+use crate::error::MyError;
+use crate::logging::{LogConfig, LogOutput};
+use crate::proxy::ProxyServer;
+use crate::ratelimit::{parse_rate, Limit, RateLimiter, RateLimits};
+use crate::resolver::{self, Dns, DnsConfig, HostEntry, System};
+use crate::router::{Outbound, Route, Router, UpstreamGroup};
+use crate::server::{Args, ServerConfig, Timeouts, User};
+use crate::tls::{client_verifier, CertIdentity, CertificateFiles};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use toml::Spanned;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 8080;
+
+/// The configuration file as written, before validation. Values whose
+/// meaning we check ourselves are kept as spanned strings so every error can
+/// point at its line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    ip: Option<Spanned<String>>,
+    port: Option<u16>,
+    socks4: Option<bool>,
+    socks5: Option<bool>,
+    http: Option<bool>,
+    tls: Option<bool>,
+    tls_cert: Option<Spanned<String>>,
+    tls_key: Option<Spanned<String>>,
+    tls_plaintext: Option<Spanned<bool>>,
+    tls_client_ca: Option<Spanned<String>>,
+    tls_client_identity: Option<Spanned<String>>,
+    auth: Option<Spanned<bool>>,
+    users: Vec<Spanned<String>>,
+    users_file: Option<Spanned<String>>,
+    acl_default: Option<Spanned<String>>,
+    rules: Vec<Spanned<String>>,
+    upstreams: Vec<Spanned<String>>,
+    upstream_groups: BTreeMap<Spanned<String>, Vec<Spanned<String>>>,
+    routes: Vec<Spanned<String>>,
+    timeouts: RawTimeouts,
+    listeners: Vec<Spanned<RawListener>>,
+    log: RawLog,
+    access_log: Option<RawLogOutput>,
+    metrics: Option<Spanned<String>>,
+    admin: Option<Spanned<String>>,
+    rate_limits: RawRateLimits,
+    conn_limits: RawConnLimits,
+    dns: RawDns,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDns {
+    system: Option<bool>,
+    servers: Vec<Spanned<String>>,
+    family: Option<Spanned<String>>,
+    cache_size: Option<usize>,
+    /// Seconds
+    negative_ttl: Option<u64>,
+    hosts: BTreeMap<String, Vec<Spanned<String>>>,
+}
+
+/// Most concurrent connections of each scope
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConnLimits {
+    global: Option<Spanned<usize>>,
+    listener: Option<Spanned<usize>>,
+    user: Option<Spanned<usize>>,
+    ip: Option<Spanned<usize>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawRateLimits {
+    global: RawLimit,
+    listener: RawLimit,
+    user: RawLimit,
+    ip: RawLimit,
+}
+
+/// Rates such as "10M", see `parse_rate`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawLimit {
+    up: Option<Spanned<String>>,
+    down: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawLog {
+    level: Option<Spanned<String>>,
+    format: Option<Spanned<String>>,
+    file: Option<String>,
+    rotation: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawLogOutput {
+    format: Option<Spanned<String>>,
+    file: Option<String>,
+    rotation: Option<Spanned<String>>,
+}
+
+/// Timeouts in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawTimeouts {
+    init: Option<Spanned<u64>>,
+    handshake: Option<Spanned<u64>>,
+    connect: Option<Spanned<u64>>,
+    bind: Option<Spanned<u64>>,
+    /// 0 turns these off
+    idle: Option<Spanned<u64>>,
+    max_session: Option<Spanned<u64>>,
+    drain: Option<Spanned<u64>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawListener {
+    ip: Option<Spanned<String>>,
+    port: Option<u16>,
+    socks4: Option<bool>,
+    socks5: Option<bool>,
+    http: Option<bool>,
+    tls: Option<bool>,
+}
+
+/// A problem found in the configuration, with the line and column it was
+/// found at when it comes from the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: Option<PathBuf>,
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+            if let Some((line, col)) = self.location {
+                write!(f, "{}:{}:", line, col)?;
+            }
+            f.write_str(" ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub socks4: bool,
+    pub socks5: bool,
+    pub http: bool,
+    /// Serve the protocols inside TLS
+    pub tls: bool,
+}
+
+/// Validated settings from the configuration file with command line flags
+/// applied on top
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listeners: Vec<Listener>,
+    /// PEM certificate chain and private key for TLS listeners, reloaded
+    /// when they change
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Serve clients of TLS listeners that don't start a TLS handshake in
+    /// plaintext. Never with passwords or client certificates.
+    pub tls_plaintext: bool,
+    /// PEM CA certificates that client certificates are verified against,
+    /// asking TLS clients for one
+    pub tls_client_ca: Option<PathBuf>,
+    /// The certificate field naming the user
+    pub tls_client_identity: CertIdentity,
+    pub auth: bool,
+    pub users: Vec<User>,
+    pub users_file: Option<PathBuf>,
+    pub acl: Acl,
+    pub upstreams: Vec<Upstream>,
+    pub upstream_groups: Vec<UpstreamGroup>,
+    pub routes: Vec<Route>,
+    pub timeouts: Timeouts,
+    pub log: LogConfig,
+    /// Address of the Prometheus endpoint, disabled when not set
+    pub metrics: Option<SocketAddr>,
+    /// Address of the admin API, disabled when not set
+    pub admin: Option<AdminAddr>,
+    pub rate_limits: RateLimits,
+    pub conn_limits: ConnLimits,
+    pub dns: DnsConfig,
+    /// Use the operating system's resolver instead of `dns`
+    pub system_resolver: bool,
+    origins: Origins,
+}
+
+/// Where in the file settings were read from, so the checks on the combined
+/// settings can point at the line to fix. Keys are paths like
+/// `timeouts.init` or `routes.2`, and settings a flag replaced have none.
+#[derive(Debug, Clone, Default)]
+struct Origins {
+    path: Option<PathBuf>,
+    locations: HashMap<String, (usize, usize)>,
+}
+
+impl Origins {
+    /// Forget `key` and everything under it
+    fn forget(&mut self, key: &str) {
+        let prefix = format!("{}.", key);
+        self.locations
+            .retain(|k, _| k != key && !k.starts_with(&prefix));
+    }
+}
+
+/// Collects errors, and the locations of settings checked later, while
+/// resolving the file
+struct Resolver<'a> {
+    path: &'a Path,
+    source: &'a str,
+    errors: Vec<ConfigError>,
+    locations: HashMap<String, (usize, usize)>,
+}
+
+impl<'a> Resolver<'a> {
+    fn locate(&mut self, key: impl Into<String>, span: Range<usize>) {
+        let location = line_col(self.source, span.start);
+        self.locations.insert(key.into(), location);
+    }
+
+    fn path(&mut self, key: &str, value: Option<Spanned<String>>) -> Option<PathBuf> {
+        let value = value?;
+        self.locate(key, value.span());
+        Some(PathBuf::from(value.into_inner()))
+    }
+
+    fn secs(&mut self, key: &str, value: &Option<Spanned<u64>>) -> Option<u64> {
+        let value = value.as_ref()?;
+        self.locate(key, value.span());
+        Some(*value.get_ref())
+    }
+
+    fn error(&mut self, span: Option<Range<usize>>, message: String) {
+        let location = span.map(|span| line_col(self.source, span.start));
+
+        self.errors.push(ConfigError {
+            path: Some(self.path.to_owned()),
+            location,
+            message,
+        });
+    }
+
+    fn parse<T: FromStr>(&mut self, value: &Spanned<String>, what: &str) -> Option<T> {
+        match value.get_ref().parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.error(
+                    Some(value.span()),
+                    format!("invalid {} \"{}\"", what, value.get_ref()),
+                );
+                None
+            }
+        }
+    }
+
+    fn log_output(&mut self, raw: &RawLogOutput) -> LogOutput {
+        let default = LogOutput::default();
+
+        LogOutput {
+            format: raw
+                .format
+                .as_ref()
+                .and_then(|f| self.parse(f, "log format"))
+                .unwrap_or(default.format),
+            file: raw.file.as_ref().map(PathBuf::from),
+            rotation: raw
+                .rotation
+                .as_ref()
+                .and_then(|f| self.parse(f, "log rotation"))
+                .unwrap_or(default.rotation),
+        }
+    }
+
+    fn rate(&mut self, value: &Option<Spanned<String>>) -> Option<u64> {
+        let value = value.as_ref()?;
+
+        match parse_rate(value.get_ref()) {
+            Ok(rate) => Some(rate),
+            Err(_) => {
+                self.error(
+                    Some(value.span()),
+                    format!("invalid rate \"{}\"", value.get_ref()),
+                );
+                None
+            }
+        }
+    }
+
+    fn limit(&mut self, raw: &RawLimit) -> Limit {
+        Limit {
+            up: self.rate(&raw.up),
+            down: self.rate(&raw.down),
+        }
+    }
+
+    fn conn_limit(&mut self, value: &Option<Spanned<usize>>) -> Option<usize> {
+        let value = value.as_ref()?;
+
+        if *value.get_ref() == 0 {
+            self.error(
+                Some(value.span()),
+                "connection limit must be at least 1".to_owned(),
+            );
+            return None;
+        }
+
+        Some(*value.get_ref())
+    }
+
+    fn parse_all<T: FromStr>(&mut self, values: &[Spanned<String>], what: &str) -> Vec<T> {
+        values
+            .iter()
+            .filter_map(|value| self.parse(value, what))
+            .collect()
+    }
+}
+
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, col)
+}
+
+/// Seconds for a timeout that 0 turns off
+fn optional_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn plain_error(message: impl Into<String>) -> ConfigError {
+    ConfigError {
+        path: None,
+        location: None,
+        message: message.into(),
+    }
+}
+
+impl Config {
+    /// Read `--config` if given, then apply the other flags over it. Errors
+    /// in the file and in the combined settings are reported together.
+    pub fn from_args(args: &Args) -> Result<Self, Vec<ConfigError>> {
+        let (mut config, mut errors) = match &args.config {
+            Some(path) => Config::read(path)?,
+            None => (Config::default(), Vec::new()),
+        };
+
+        if config.listeners.len() > 1 && (args.ip.is_some() || args.port.is_some()) {
+            errors.push(plain_error(
+                "--ip and --port can't be used with a file that has several listeners",
+            ));
+        }
+
+        config.apply_args(args);
+        config.checked(errors)
+    }
+
+    /// Parse and check a configuration file, reporting every error found
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Vec<ConfigError>> {
+        let (config, errors) = Config::read(path.as_ref())?;
+        config.checked(errors)
+    }
+
+    /// Parse a configuration file, along with the errors in values that
+    /// could be read around. Fails only when the file can't be read at all.
+    fn read(path: &Path) -> Result<(Self, Vec<ConfigError>), Vec<ConfigError>> {
+        let source = std::fs::read_to_string(path).map_err(|e| {
+            vec![ConfigError {
+                path: Some(path.to_owned()),
+                location: None,
+                message: format!("couldn't read file: {}", e),
+            }]
+        })?;
+
+        Config::parse(path, &source)
+    }
+
+    fn parse(path: &Path, source: &str) -> Result<(Self, Vec<ConfigError>), Vec<ConfigError>> {
+        let mut resolver = Resolver {
+            path,
+            source,
+            errors: Vec::new(),
+            locations: HashMap::new(),
+        };
+
+        let raw: RawConfig = match toml::from_str(source) {
+            Ok(raw) => raw,
+            Err(e) => {
+                resolver.error(e.span(), e.message().to_owned());
+                return Err(resolver.errors);
+            }
+        };
+
+        let mut config = Config::resolve(raw, &mut resolver);
+        config.origins = Origins {
+            path: Some(path.to_owned()),
+            locations: resolver.locations,
+        };
+
+        Ok((config, resolver.errors))
+    }
+
+    /// Run `validate` and fail with its errors and the `earlier` ones
+    fn checked(self, mut earlier: Vec<ConfigError>) -> Result<Self, Vec<ConfigError>> {
+        if let Err(errors) = self.validate() {
+            earlier.extend(errors);
+        }
+
+        if earlier.is_empty() {
+            Ok(self)
+        } else {
+            earlier.sort_by_key(|e| e.location);
+            Err(earlier)
+        }
+    }
+
+    /// Whether TLS listeners may serve plaintext clients: not when they
+    /// could send passwords, or skip a client certificate
+    fn plaintext_allowed(&self) -> bool {
+        !self.auth
+            && self.users.is_empty()
+            && self.users_file.is_none()
+            && self.tls_client_ca.is_none()
+    }
+
+    /// An error about the setting at `key`, pointing at it when it came
+    /// from the file
+    fn error(&self, key: &str, message: impl Into<String>) -> ConfigError {
+        match self.origins.locations.get(key) {
+            Some(location) => ConfigError {
+                path: self.origins.path.clone(),
+                location: Some(*location),
+                message: message.into(),
+            },
+            None => plain_error(message),
+        }
+    }
+
+    fn resolve(raw: RawConfig, r: &mut Resolver) -> Self {
+        let ip = raw
+            .ip
+            .as_ref()
+            .and_then(|ip| r.parse::<IpAddr>(ip, "IP address"))
+            .unwrap_or(DEFAULT_IP);
+        let port = raw.port.unwrap_or(DEFAULT_PORT);
+        let socks4 = raw.socks4.unwrap_or(false);
+        let socks5 = raw.socks5.unwrap_or(false);
+        let http = raw.http.unwrap_or(false);
+        let tls = raw.tls.unwrap_or(false);
+
+        let listeners = if raw.listeners.is_empty() {
+            vec![Listener {
+                addr: SocketAddr::new(ip, port),
+                socks4,
+                socks5,
+                http,
+                tls,
+            }]
+        } else {
+            // top level settings are the defaults for each listener
+            raw.listeners
+                .iter()
+                .enumerate()
+                .map(|(i, l)| {
+                    r.locate(format!("listeners.{}", i), l.span());
+                    let l = l.get_ref();
+
+                    Listener {
+                        addr: SocketAddr::new(
+                            l.ip.as_ref()
+                                .and_then(|ip| r.parse::<IpAddr>(ip, "IP address"))
+                                .unwrap_or(ip),
+                            l.port.unwrap_or(port),
+                        ),
+                        socks4: l.socks4.unwrap_or(socks4),
+                        socks5: l.socks5.unwrap_or(socks5),
+                        http: l.http.unwrap_or(http),
+                        tls: l.tls.unwrap_or(tls),
+                    }
+                })
+                .collect()
+        };
+
+        let upstream_groups = raw
+            .upstream_groups
+            .iter()
+            .map(|(name, hops)| UpstreamGroup {
+                name: name.get_ref().clone(),
+                hops: r.parse_all(hops, "upstream"),
+            })
+            .collect::<Vec<_>>();
+
+        for name in raw.upstream_groups.keys() {
+            if matches!(
+                name.get_ref().parse(),
+                Ok(Outbound::Direct | Outbound::Reject)
+            ) {
+                r.error(
+                    Some(name.span()),
+                    format!("upstream group can't be named \"{}\"", name.get_ref()),
+                );
+            }
+        }
+
+        // unknown groups are caught by `validate`, after the flags
+        let mut routes = Vec::new();
+        for value in &raw.routes {
+            if let Some(route) = r.parse::<Route>(value, "route") {
+                r.locate(format!("routes.{}", routes.len()), value.span());
+                routes.push(route);
+            }
+        }
+
+        if let Some(level) = &raw.log.level {
+            r.locate("log.level", level.span());
+        }
+        if let Some(admin) = &raw.admin {
+            r.locate("admin", admin.span());
+        }
+        if let Some(auth) = &raw.auth {
+            r.locate("auth", auth.span());
+        }
+        if let Some(plaintext) = &raw.tls_plaintext {
+            r.locate("tls_plaintext", plaintext.span());
+        }
+
+        let log = LogConfig {
+            level: raw
+                .log
+                .level
+                .map_or_else(|| LogConfig::default().level, Spanned::into_inner),
+            output: r.log_output(&RawLogOutput {
+                format: raw.log.format,
+                file: raw.log.file,
+                rotation: raw.log.rotation,
+            }),
+            access: raw.access_log.as_ref().map(|access| r.log_output(access)),
+        };
+
+        let dns_default = DnsConfig::default();
+        let dns = DnsConfig {
+            servers: r.parse_all(&raw.dns.servers, "DNS server"),
+            hosts: raw
+                .dns
+                .hosts
+                .iter()
+                .map(|(name, addrs)| HostEntry {
+                    name: name.trim_end_matches('.').to_ascii_lowercase(),
+                    addrs: r.parse_all(addrs, "IP address"),
+                })
+                .collect(),
+            family: raw
+                .dns
+                .family
+                .as_ref()
+                .and_then(|f| r.parse(f, "address family"))
+                .unwrap_or(dns_default.family),
+            cache_size: raw.dns.cache_size.unwrap_or(dns_default.cache_size),
+            negative_ttl: raw
+                .dns
+                .negative_ttl
+                .map_or(dns_default.negative_ttl, Duration::from_secs),
+        };
+
+        let default = Timeouts::default();
+        let t = &raw.timeouts;
+
+        Config {
+            listeners,
+            tls_cert: r.path("tls_cert", raw.tls_cert),
+            tls_key: r.path("tls_key", raw.tls_key),
+            tls_plaintext: raw.tls_plaintext.is_some_and(Spanned::into_inner),
+            tls_client_ca: r.path("tls_client_ca", raw.tls_client_ca),
+            tls_client_identity: raw
+                .tls_client_identity
+                .as_ref()
+                .and_then(|f| r.parse(f, "certificate field"))
+                .unwrap_or_default(),
+            auth: raw.auth.is_some_and(Spanned::into_inner),
+            users: r.parse_all(&raw.users, "user"),
+            users_file: r.path("users_file", raw.users_file),
+            acl: Acl::new(
+                r.parse_all(&raw.rules, "rule"),
+                raw.acl_default
+                    .as_ref()
+                    .and_then(|d| r.parse::<Action>(d, "action"))
+                    .unwrap_or(Action::Allow),
+            ),
+            upstreams: r.parse_all(&raw.upstreams, "upstream"),
+            upstream_groups,
+            routes,
+            timeouts: Timeouts {
+                init: r
+                    .secs("timeouts.init", &t.init)
+                    .map_or(default.init, Duration::from_secs),
+                handshake: r
+                    .secs("timeouts.handshake", &t.handshake)
+                    .map_or(default.handshake, Duration::from_secs),
+                connect: r
+                    .secs("timeouts.connect", &t.connect)
+                    .map_or(default.connect, Duration::from_secs),
+                bind: r
+                    .secs("timeouts.bind", &t.bind)
+                    .map_or(default.bind, Duration::from_secs),
+                idle: r
+                    .secs("timeouts.idle", &t.idle)
+                    .map_or(default.idle, optional_secs),
+                max_session: r
+                    .secs("timeouts.max_session", &t.max_session)
+                    .map_or(default.max_session, optional_secs),
+                drain: r
+                    .secs("timeouts.drain", &t.drain)
+                    .map_or(default.drain, Duration::from_secs),
+            },
+            log,
+            metrics: raw
+                .metrics
+                .as_ref()
+                .and_then(|addr| r.parse(addr, "metrics address")),
+            admin: raw
+                .admin
+                .as_ref()
+                .and_then(|addr| r.parse(addr, "admin address")),
+            rate_limits: RateLimits {
+                global: r.limit(&raw.rate_limits.global),
+                listener: r.limit(&raw.rate_limits.listener),
+                user: r.limit(&raw.rate_limits.user),
+                ip: r.limit(&raw.rate_limits.ip),
+            },
+            conn_limits: ConnLimits {
+                global: r.conn_limit(&raw.conn_limits.global),
+                listener: r.conn_limit(&raw.conn_limits.listener),
+                user: r.conn_limit(&raw.conn_limits.user),
+                ip: r.conn_limit(&raw.conn_limits.ip),
+            },
+            dns,
+            system_resolver: raw.dns.system.unwrap_or(false),
+            origins: Origins::default(),
+        }
+    }
+
+    /// Flags given on the command line replace the file's values
+    fn apply_args(&mut self, args: &Args) {
+        for listener in &mut self.listeners {
+            if let Some(ip) = args.ip {
+                listener.addr.set_ip(ip);
+            }
+            if let Some(port) = args.port {
+                listener.addr.set_port(port);
+            }
+            listener.socks4 |= args.socks4;
+            listener.socks5 |= args.socks5;
+            listener.http |= args.http;
+            listener.tls |= args.tls;
+        }
+
+        if let Some(path) = &args.tls_cert {
+            self.origins.forget("tls_cert");
+            self.tls_cert = Some(path.clone());
+        }
+        if let Some(path) = &args.tls_key {
+            self.origins.forget("tls_key");
+            self.tls_key = Some(path.clone());
+        }
+        if args.tls_plaintext {
+            self.tls_plaintext = true;
+            self.origins.forget("tls_plaintext");
+        }
+        if let Some(path) = &args.tls_client_ca {
+            self.origins.forget("tls_client_ca");
+            self.tls_client_ca = Some(path.clone());
+        }
+        if let Some(field) = args.tls_client_identity {
+            self.tls_client_identity = field;
+        }
+
+        if args.auth {
+            self.auth = true;
+            self.origins.forget("auth");
+        }
+
+        if let Some(users) = &args.users {
+            self.users = users.clone();
+        }
+        if let Some(path) = &args.users_file {
+            self.origins.forget("users_file");
+            self.users_file = Some(path.clone());
+        }
+        if !args.rules.is_empty() {
+            self.acl.rules = args.rules.clone();
+        }
+        if let Some(default) = args.acl_default {
+            self.acl.default = default;
+        }
+        if !args.upstreams.is_empty() {
+            self.upstreams = args.upstreams.clone();
+        }
+        if !args.upstream_groups.is_empty() {
+            self.upstream_groups = args.upstream_groups.clone();
+        }
+        if !args.routes.is_empty() {
+            self.origins.forget("routes");
+            self.routes = args.routes.clone();
+        }
+        if let Some(init) = args.init_timeout {
+            self.origins.forget("timeouts.init");
+            self.timeouts.init = Duration::from_secs(init);
+        }
+        if let Some(handshake) = args.handshake_timeout {
+            self.origins.forget("timeouts.handshake");
+            self.timeouts.handshake = Duration::from_secs(handshake);
+        }
+        if let Some(connect) = args.connect_timeout {
+            self.origins.forget("timeouts.connect");
+            self.timeouts.connect = Duration::from_secs(connect);
+        }
+        if let Some(bind) = args.bind_timeout {
+            self.origins.forget("timeouts.bind");
+            self.timeouts.bind = Duration::from_secs(bind);
+        }
+        if let Some(idle) = args.idle_timeout {
+            self.timeouts.idle = optional_secs(idle);
+        }
+        if let Some(max) = args.max_session_duration {
+            self.timeouts.max_session = optional_secs(max);
+        }
+        if let Some(drain) = args.drain_timeout {
+            self.timeouts.drain = Duration::from_secs(drain);
+        }
+        if let Some(level) = &args.log_level {
+            self.origins.forget("log.level");
+            self.log.level = level.clone();
+        }
+        if let Some(format) = args.log_format {
+            self.log.output.format = format;
+        }
+        if let Some(path) = &args.log_file {
+            self.log.output.file = Some(path.clone());
+        }
+        if let Some(rotation) = args.log_rotation {
+            self.log.output.rotation = rotation;
+        }
+        if let Some(addr) = args.metrics {
+            self.metrics = Some(addr);
+        }
+        if let Some(addr) = &args.admin {
+            self.origins.forget("admin");
+            self.admin = Some(addr.clone());
+        }
+        for limit in &args.rate_limits {
+            self.rate_limits.set(*limit);
+        }
+        for limit in &args.conn_limits {
+            self.conn_limits.set(*limit);
+        }
+        if !args.dns_servers.is_empty() {
+            self.dns.servers = args.dns_servers.clone();
+        }
+        if let Some(family) = args.dns_family {
+            self.dns.family = family;
+        }
+        // flags add to the file's hosts, replacing entries of the same name
+        for entry in &args.hosts {
+            self.dns.hosts.retain(|e| e.name != entry.name);
+            self.dns.hosts.push(entry.clone());
+        }
+        self.system_resolver |= args.system_resolver;
+        if let Some(path) = &args.access_log {
+            // the access log follows the main log's format
+            let access = self.log.access.get_or_insert(LogOutput {
+                format: self.log.output.format,
+                rotation: self.log.output.rotation,
+                file: None,
+            });
+            access.file = Some(path.clone());
+        }
+    }
+
+    /// Checks that need the file and flags combined
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if !listener.socks4 && !listener.socks5 && !listener.http {
+                errors.push(self.error(
+                    &format!("listeners.{}", i),
+                    format!(
+                        "listener {} has neither socks4, socks5 nor http enabled",
+                        listener.addr
+                    ),
+                ));
+            }
+        }
+
+        if self.listeners.iter().any(|l| l.tls) {
+            match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => {
+                    if let Err(e) = CertificateFiles::load(cert, key) {
+                        errors.push(self.error(
+                            "tls_cert",
+                            format!(
+                                "couldn't load TLS certificate {} and key {}: {}",
+                                cert.display(),
+                                key.display(),
+                                e
+                            ),
+                        ));
+                    }
+                }
+                _ => errors.push(plain_error("TLS requires a certificate and a key")),
+            }
+
+            if self.tls_plaintext && !self.plaintext_allowed() {
+                errors.push(self.error(
+                    "tls_plaintext",
+                    "plaintext clients can't be served with auth, users or a client CA",
+                ));
+            }
+
+            if let Some(ca) = &self.tls_client_ca {
+                if let Err(e) = client_verifier(ca, self.auth) {
+                    errors.push(self.error(
+                        "tls_client_ca",
+                        format!(
+                            "couldn't load client CA certificates {}: {}",
+                            ca.display(),
+                            e
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if self.auth {
+            if self.listeners.iter().any(|l| !l.socks5 && !l.http) {
+                errors.push(self.error(
+                    "auth",
+                    "authentication requires socks5 or http on every listener",
+                ));
+            }
+            if self.users.is_empty() && self.users_file.is_none() {
+                errors.push(self.error("auth", "authentication requires users or a users file"));
+            }
+        }
+
+        if let Some(path) = &self.users_file {
+            if let Err(e) = CredentialsFile::load(path) {
+                errors.push(self.error(
+                    "users_file",
+                    format!("couldn't load users from {}: {}", path.display(), e),
+                ));
+            }
+        }
+
+        // 0 only turns off the idle and session limits
+        let timeouts = [
+            ("init", self.timeouts.init),
+            ("handshake", self.timeouts.handshake),
+            ("connect", self.timeouts.connect),
+            ("bind", self.timeouts.bind),
+        ];
+        for (name, timeout) in timeouts {
+            if timeout.is_zero() {
+                errors.push(self.error(
+                    &format!("timeouts.{}", name),
+                    format!("{} timeout must be at least 1 second", name),
+                ));
+            }
+        }
+
+        if let Some(AdminAddr::Tcp(addr)) = &self.admin {
+            // the API has no authentication of its own
+            if !addr.ip().is_loopback() {
+                errors.push(self.error(
+                    "admin",
+                    format!(
+                        "admin API must listen on a loopback address or a unix socket, not {}",
+                        addr
+                    ),
+                ));
+            }
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            errors.push(self.error(
+                "log.level",
+                format!("invalid log level \"{}\": {}", self.log.level, e),
+            ));
+        }
+
+        for (i, route) in self.routes.iter().enumerate() {
+            if let Outbound::Group(name) = &route.outbound {
+                if !self.upstream_groups.iter().any(|g| g.name == *name) {
+                    errors.push(self.error(
+                        &format!("routes.{}", i),
+                        format!("route uses unknown upstream group \"{}\"", name),
+                    ));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Build the servers for each listener. They share one set of policy,
+    /// and the users file and TLS certificate are watched for changes, so
+    /// this must be called from within a tokio runtime.
+    pub fn proxies(&self) -> Result<Vec<ProxyServer>, MyError> {
+        let resolver: Arc<dyn resolver::Resolver> = if self.system_resolver {
+            Arc::new(System)
+        } else {
+            match Dns::new(&self.dns) {
+                Ok(dns) => Arc::new(dns),
+                Err(_) => {
+                    warn!("couldn't read /etc/resolv.conf, using the system resolver");
+                    Arc::new(System)
+                }
+            }
+        };
+        let direct = Direct::new(resolver.clone());
+
+        let mut server = ServerConfig {
+            timeouts: self.timeouts,
+            acl: self.acl.clone(),
+            router: Router::new(
+                self.routes.clone(),
+                self.upstream_groups.clone(),
+                direct.clone(),
+            )?,
+            connector: Arc::new(direct.clone()),
+            resolver,
+            rate_limiter: RateLimiter::new(self.rate_limits),
+            conn_limiter: ConnLimiter::new(self.conn_limits),
+            ..ServerConfig::default()
+        };
+
+        if !self.upstreams.is_empty() {
+            server.connector = Arc::new(Chain::new(self.upstreams.clone()).via(direct));
+        }
+
+        if self.auth {
+            server.authenticator = Some(match &self.users_file {
+                Some(path) => Arc::new(CredentialsFile::load(path)?.watch()),
+                None => Arc::new(StaticUsers::new(self.users.clone())),
+            });
+        }
+
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) if self.listeners.iter().any(|l| l.tls) => {
+                // with passwords to fall back on a certificate is optional
+                let verifier = match &self.tls_client_ca {
+                    Some(ca) => Some(client_verifier(ca, self.auth)?),
+                    None => None,
+                };
+                let certs = CertificateFiles::load(cert, key)?.watch();
+                Some(certs.server_config(verifier))
+            }
+            _ => None,
+        };
+
+        Ok(self
+            .listeners
+            .iter()
+            .map(|l| {
+                let proxy = ProxyServer::new()
+                    .bind(l.addr)
+                    .socks4(l.socks4)
+                    .socks5(l.socks5)
+                    .http(l.http)
+                    .client_identity(self.tls_client_identity)
+                    .config(server.clone());
+
+                match &tls {
+                    Some(config) if l.tls => proxy
+                        .tls(config.clone())
+                        .plaintext(self.tls_plaintext && self.plaintext_allowed()),
+                    _ => proxy,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: vec![Listener {
+                addr: SocketAddr::new(DEFAULT_IP, DEFAULT_PORT),
+                socks4: false,
+                socks5: false,
+                http: false,
+                tls: false,
+            }],
+            tls_cert: None,
+            tls_key: None,
+            tls_plaintext: false,
+            tls_client_ca: None,
+            tls_client_identity: CertIdentity::default(),
+            auth: false,
+            users: Vec::new(),
+            users_file: None,
+            acl: Acl::default(),
+            upstreams: Vec::new(),
+            upstream_groups: Vec::new(),
+            routes: Vec::new(),
+            timeouts: Timeouts::default(),
+            log: LogConfig::default(),
+            metrics: None,
+            admin: None,
+            rate_limits: RateLimits::default(),
+            conn_limits: ConnLimits::default(),
+            dns: DnsConfig::default(),
+            system_resolver: false,
+            origins: Origins::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn check(source: &str) -> Result<Config, Vec<ConfigError>> {
+        let (config, errors) = Config::parse(Path::new("test.toml"), source)?;
+        config.checked(errors)
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        check(source)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        Config::load(concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn combined_errors_point_at_their_line() {
+        let errors = messages("socks5 = true\nroutes = [\"missing port=25\"]\n");
+        assert_eq!(
+            errors,
+            ["test.toml:2:11: route uses unknown upstream group \"missing\""]
+        );
+    }
+
+    #[test]
+    fn reserved_group_names_point_at_their_key() {
+        let errors = messages(
+            "socks5 = true\n[upstream_groups]\ncorp = [\"socks5://10.0.0.1:1080\"]\n\
+             direct = [\"socks5://10.0.0.2:1080\"]\n",
+        );
+        assert_eq!(
+            errors,
+            ["test.toml:4:1: upstream group can't be named \"direct\""]
+        );
+    }
+
+    #[test]
+    fn file_and_combined_errors_are_reported_together() {
+        let errors = messages("socks5 = true\nrules = [\"bogus\"]\n[timeouts]\nconnect = 0\n");
+        assert_eq!(
+            errors,
+            [
+                "test.toml:2:10: invalid rule \"bogus\"",
+                "test.toml:4:11: connect timeout must be at least 1 second",
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_turns_off_only_idle_and_session_limits() {
+        check("socks5 = true\n[timeouts]\nidle = 0\nmax_session = 0\n").unwrap();
+
+        for name in ["init", "handshake", "connect", "bind"] {
+            let errors = messages(&format!("socks5 = true\n[timeouts]\n{} = 0\n", name));
+            assert_eq!(
+                errors,
+                [format!(
+                    "test.toml:3:{}: {} timeout must be at least 1 second",
+                    name.len() + 4,
+                    name
+                )]
+            );
+        }
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn tls_plaintext_is_refused_with_passwords() {
+        let source = format!(
+            "socks5 = true\ntls = true\ntls_cert = {:?}\ntls_key = {:?}\n\
+             tls_plaintext = true\nauth = true\nusers = [\"alice:secret\"]\n",
+            testdata("server.pem"),
+            testdata("server-key.pem"),
+        );
+        assert_eq!(
+            messages(&source),
+            ["test.toml:5:17: plaintext clients can't be served with auth, users or a client CA"]
+        );
+
+        let source = source.replace("auth = true\nusers = [\"alice:secret\"]\n", "");
+        check(&source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tls_listeners_with_passwords_close_plaintext_clients() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        // as if validation had been skipped
+        let config = Config {
+            listeners: vec![Listener {
+                addr: "127.0.0.1:0".parse().unwrap(),
+                socks4: false,
+                socks5: true,
+                http: false,
+                tls: true,
+            }],
+            tls_cert: Some(testdata("server.pem")),
+            tls_key: Some(testdata("server-key.pem")),
+            tls_plaintext: true,
+            auth: true,
+            users: vec!["alice:secret".parse().unwrap()],
+            ..Config::default()
+        };
+        let proxy = config.proxies().unwrap().remove(0).start().await.unwrap();
+
+        let mut client = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        // closing with the greeting unread resets the connection
+        let read = client.read(&mut [0u8; 2]).await.unwrap_or(0);
+        assert_eq!(read, 0);
+    }
+
+    #[test]
+    fn flags_replace_file_locations() {
+        let (mut config, _) = Config::parse(
+            Path::new("test.toml"),
+            "socks5 = true\n[timeouts]\ninit = 5\n",
+        )
+        .unwrap();
+        config.apply_args(&Args::parse_from([
+            "socks-proxy-server",
+            "--init-timeout",
+            "0",
+        ]));
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            [plain_error("init timeout must be at least 1 second")]
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use crate::error::MyError;
+use crate::ratelimit::Scope;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A cap on concurrent connections for one scope, written `scope=N` such as
+/// `ip=50`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ScopedConnLimit {
+    pub scope: Scope,
+    pub max: usize,
+}
+
+impl FromStr for ScopedConnLimit {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scope, max) = s.split_once('=').ok_or(MyError::Parse)?;
+        let max = max.trim().parse()?;
+
+        if max == 0 {
+            return Err(MyError::Parse);
+        }
+
+        Ok(ScopedConnLimit {
+            scope: scope.trim().parse()?,
+            max,
+        })
+    }
+}
+
+/// The most concurrent connections of every scope, `None` for unlimited
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct ConnLimits {
+    pub global: Option<usize>,
+    pub listener: Option<usize>,
+    pub user: Option<usize>,
+    pub ip: Option<usize>,
+}
+
+impl ConnLimits {
+    pub fn set(&mut self, scoped: ScopedConnLimit) {
+        let max = match scoped.scope {
+            Scope::Global => &mut self.global,
+            Scope::Listener => &mut self.listener,
+            Scope::User => &mut self.user,
+            Scope::Ip => &mut self.ip,
+        };
+        *max = Some(scoped.max);
+    }
+}
+
+fn acquire(count: &AtomicUsize, max: usize) -> bool {
+    count
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            (n < max).then_some(n + 1)
+        })
+        .is_ok()
+}
+
+/// Counts per key. Keys are removed once their count drops to zero.
+#[derive(Debug)]
+struct Keyed<K> {
+    counts: Mutex<HashMap<K, usize>>,
+}
+
+impl<K: Hash + Eq> Keyed<K> {
+    fn new() -> Self {
+        Keyed {
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn acquire(&self, key: K, max: usize) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key).or_insert(0);
+
+        if *count < max {
+            *count += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn release(&self, key: &K) {
+        let mut counts = self.counts.lock().unwrap();
+
+        if let Some(count) = counts.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(key);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    limits: ConnLimits,
+    global: AtomicUsize,
+    users: Keyed<String>,
+    ips: Keyed<IpAddr>,
+}
+
+/// Counts open connections against the limits. Clones share the global,
+/// user and IP counts, listener counts belong to each listener.
+#[derive(Debug, Clone)]
+pub struct ConnLimiter {
+    shared: Arc<Shared>,
+}
+
+impl Default for ConnLimiter {
+    fn default() -> Self {
+        Self::new(ConnLimits::default())
+    }
+}
+
+impl ConnLimiter {
+    pub fn new(limits: ConnLimits) -> Self {
+        ConnLimiter {
+            shared: Arc::new(Shared {
+                limits,
+                global: AtomicUsize::new(0),
+                users: Keyed::new(),
+                ips: Keyed::new(),
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnLimits {
+        &self.shared.limits
+    }
+
+    /// Counts for a new listener
+    pub fn listener(&self) -> ListenerConnLimiter {
+        ListenerConnLimiter {
+            limiter: self.clone(),
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Slot {
+    Global,
+    Listener(Arc<AtomicUsize>),
+    User(String),
+    Ip(IpAddr),
+}
+
+/// A place under the limits, given back when dropped
+#[derive(Debug)]
+pub struct ConnPermit {
+    shared: Arc<Shared>,
+    slots: Vec<Slot>,
+}
+
+impl ConnPermit {
+    fn new(shared: &Arc<Shared>) -> Self {
+        ConnPermit {
+            shared: shared.clone(),
+            slots: Vec::new(),
+        }
+    }
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        for slot in &self.slots {
+            match slot {
+                Slot::Global => {
+                    self.shared.global.fetch_sub(1, Ordering::AcqRel);
+                }
+                Slot::Listener(count) => {
+                    count.fetch_sub(1, Ordering::AcqRel);
+                }
+                Slot::User(user) => self.shared.users.release(user),
+                Slot::Ip(ip) => self.shared.ips.release(ip),
+            }
+        }
+    }
+}
+
+/// The connection limiter of one listener
+#[derive(Debug, Clone)]
+pub struct ListenerConnLimiter {
+    limiter: ConnLimiter,
+    count: Arc<AtomicUsize>,
+}
+
+impl ListenerConnLimiter {
+    /// Take a place for a new connection from `client` under the global,
+    /// listener and IP limits, or name the scope that is full
+    pub fn accept(&self, client: IpAddr) -> Result<ConnPermit, Scope> {
+        let shared = &self.limiter.shared;
+        let limits = &shared.limits;
+        // places taken so far are given back if a later scope is full
+        let mut permit = ConnPermit::new(shared);
+
+        if let Some(max) = limits.global {
+            if !acquire(&shared.global, max) {
+                return Err(Scope::Global);
+            }
+            permit.slots.push(Slot::Global);
+        }
+
+        if let Some(max) = limits.listener {
+            if !acquire(&self.count, max) {
+                return Err(Scope::Listener);
+            }
+            permit.slots.push(Slot::Listener(self.count.clone()));
+        }
+
+        if let Some(max) = limits.ip {
+            if !shared.ips.acquire(client, max) {
+                return Err(Scope::Ip);
+            }
+            permit.slots.push(Slot::Ip(client));
+        }
+
+        Ok(permit)
+    }
+
+    /// Take a place for a connection authenticated as `user`
+    pub fn user(&self, user: &str) -> Result<ConnPermit, Scope> {
+        let shared = &self.limiter.shared;
+        let mut permit = ConnPermit::new(shared);
+
+        if let Some(max) = shared.limits.user {
+            if !shared.users.acquire(user.to_owned(), max) {
+                return Err(Scope::User);
+            }
+            permit.slots.push(Slot::User(user.to_owned()));
+        }
+
+        Ok(permit)
+    }
+}
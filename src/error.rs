@@ -1,4 +1,5 @@
 use nom::error::ErrorKind;
+use std::num::ParseIntError;
 use thiserror::Error;
 use tokio::time::error::Elapsed;
 
@@ -22,6 +23,12 @@ impl From<std::io::Error> for MyError {
     }
 }
 
+impl From<ParseIntError> for MyError {
+    fn from(_: ParseIntError) -> Self {
+        MyError::Parse
+    }
+}
+
 impl From<Elapsed> for MyError {
     fn from(_: Elapsed) -> Self {
         MyError::Timeout
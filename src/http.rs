@@ -0,0 +1,596 @@
+use crate::error::MyError;
+use crate::ratelimit::Throttle;
+use crate::server::User;
+use crate::socks::Destination;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+// longest request head we accept from a client
+const MAX_HEAD: usize = 8192;
+// longest chunk size or trailer line of a chunked body
+const MAX_LINE: usize = 4096;
+const MAX_HEADERS: usize = 64;
+const HTTP_PORT: u16 = 80;
+
+/// Headers meant for the proxy, or for this one connection, that aren't
+/// passed on to the origin server
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Read a request head up to the blank line that ends it. Anything the
+/// client sent after it, such as a body, is left in `reader`.
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, MyError> {
+    let mut head = Vec::new();
+
+    while !(head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n")) {
+        let limit = (MAX_HEAD - head.len()) as u64;
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut head)
+            .await?;
+
+        if n == 0 {
+            // closed early, or too long
+            return Err(if head.len() < MAX_HEAD {
+                MyError::IO
+            } else {
+                MyError::Parse
+            });
+        }
+    }
+
+    Ok(head)
+}
+
+/// How the body of a forwarded request ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestBody {
+    Empty,
+    Length(u64),
+    Chunked,
+}
+
+/// Copy a request body framed as `body` from `reader` to `writer`
+/// unchanged, chunk sizes and trailers included, and nothing after it.
+/// Holds to `throttle` and counts bytes with `count` as they go.
+pub async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    body: RequestBody,
+    throttle: &Throttle,
+    count: impl Fn(u64),
+) -> Result<(), MyError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut pass = Pass {
+        writer,
+        throttle,
+        count,
+    };
+
+    match body {
+        RequestBody::Empty => Ok(()),
+        RequestBody::Length(len) => pass.exactly(reader, len).await,
+        RequestBody::Chunked => loop {
+            let line = read_line(reader).await?;
+            pass.bytes(&line).await?;
+
+            let size = chunk_size(&line)?;
+            if size == 0 {
+                // trailers, up to an empty line
+                loop {
+                    let line = read_line(reader).await?;
+                    pass.bytes(&line).await?;
+                    if line == b"\r\n" || line == b"\n" {
+                        return Ok(());
+                    }
+                }
+            }
+
+            pass.exactly(reader, size).await?;
+            let end = read_line(reader).await?;
+            if end != b"\r\n" && end != b"\n" {
+                return Err(MyError::Parse);
+            }
+            pass.bytes(&end).await?;
+        },
+    }
+}
+
+/// The writing end of `copy_body`
+struct Pass<'a, W, C> {
+    writer: &'a mut W,
+    throttle: &'a Throttle,
+    count: C,
+}
+
+impl<W: AsyncWrite + Unpin, C: Fn(u64)> Pass<'_, W, C> {
+    async fn bytes(&mut self, buf: &[u8]) -> Result<(), MyError> {
+        self.throttle.take(buf.len() as u64).await;
+        self.writer.write_all(buf).await?;
+        (self.count)(buf.len() as u64);
+        Ok(())
+    }
+
+    async fn exactly<R: AsyncBufRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        mut len: u64,
+    ) -> Result<(), MyError> {
+        while len > 0 {
+            let buf = reader.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(MyError::IO);
+            }
+
+            let n = buf.len().min(usize::try_from(len).unwrap_or(usize::MAX));
+            self.bytes(&buf[..n]).await?;
+            reader.consume_unpin(n);
+            len -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// One line of a chunked body, with its line ending
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, MyError> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    match line.last() {
+        Some(b'\n') => Ok(line),
+        // closed early
+        None => Err(MyError::IO),
+        _ if line.len() < MAX_LINE => Err(MyError::IO),
+        _ => Err(MyError::Parse),
+    }
+}
+
+/// The size on a chunk size line such as `1a;name=value\r\n`
+fn chunk_size(line: &[u8]) -> Result<u64, MyError> {
+    let line = std::str::from_utf8(line).map_err(|_| MyError::Parse)?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(MyError::Parse);
+    }
+    u64::from_str_radix(size, 16).map_err(|_| MyError::Parse)
+}
+
+/// A request to the proxy, such as `CONNECT example.com:443 HTTP/1.1` or
+/// `GET http://example.com/ HTTP/1.1`
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    /// Minor version, 0 or 1
+    pub version: u8,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl HttpRequest {
+    pub fn parse(head: &[u8]) -> Result<Self, MyError> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+
+        match req.parse(head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            _ => return Err(MyError::Parse),
+        }
+
+        Ok(HttpRequest {
+            method: req.method.ok_or(MyError::Parse)?.to_owned(),
+            target: req.path.ok_or(MyError::Parse)?.to_owned(),
+            version: req.version.ok_or(MyError::Parse)?,
+            headers: req
+                .headers
+                .iter()
+                .map(|h| (h.name.to_owned(), h.value.to_vec()))
+                .collect(),
+        })
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    /// The first value of the header `name` if it is valid UTF-8
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| std::str::from_utf8(v).ok())
+    }
+
+    /// Where the request goes: the `host:port` of a CONNECT, or the host of
+    /// an absolute `http://` URI. Other targets aren't proxied.
+    pub fn destination(&self) -> Result<Destination, MyError> {
+        if self.is_connect() {
+            return self.target.parse();
+        }
+
+        let authority = self.authority().ok_or(MyError::Parse)?;
+
+        match authority.parse() {
+            Ok(dest) => Ok(dest),
+            // no port
+            Err(_) => format!("{}:{}", authority, HTTP_PORT).parse(),
+        }
+    }
+
+    fn authority(&self) -> Option<&str> {
+        let (scheme, rest) = self.target.split_once("://")?;
+        if !scheme.eq_ignore_ascii_case("http") {
+            return None;
+        }
+
+        let authority = rest.split(['/', '?', '#']).next()?;
+        // credentials in the URI are not ours to use
+        let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+        (!authority.is_empty()).then_some(authority)
+    }
+
+    /// How the request's body ends. Requests the proxy can't frame
+    /// unambiguously, with both a length and a transfer coding or with
+    /// conflicting lengths, are refused rather than passed on.
+    pub fn body(&self) -> Result<RequestBody, MyError> {
+        let values = |name: &'static str| {
+            self.headers
+                .iter()
+                .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| std::str::from_utf8(v).map_err(|_| MyError::Parse))
+        };
+
+        let codings = values("transfer-encoding")
+            .collect::<Result<Vec<_>, _>>()?
+            .join(",");
+        let lengths = values("content-length").collect::<Result<Vec<_>, _>>()?;
+
+        if !codings.trim().is_empty() {
+            // chunked must come last, it is what ends the body
+            let last = codings.rsplit(',').next().unwrap_or_default().trim();
+            if !lengths.is_empty() || !last.eq_ignore_ascii_case("chunked") {
+                return Err(MyError::Parse);
+            }
+            return Ok(RequestBody::Chunked);
+        }
+
+        let mut length = None;
+        for value in lengths.iter().flat_map(|v| v.split(',')) {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(MyError::Parse);
+            }
+            let value: u64 = value.parse().map_err(|_| MyError::Parse)?;
+            if length.is_some_and(|l| l != value) {
+                return Err(MyError::Parse);
+            }
+            length = Some(value);
+        }
+
+        Ok(match length {
+            Some(0) | None => RequestBody::Empty,
+            Some(len) => RequestBody::Length(len),
+        })
+    }
+
+    /// Credentials of a `Proxy-Authorization: Basic` header
+    pub fn credentials(&self) -> Option<User> {
+        let value = self.header("proxy-authorization")?.trim();
+        let (scheme, encoded) = value.split_once(' ')?;
+
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let decoded = STANDARD.decode(encoded.trim()).ok()?;
+        String::from_utf8(decoded).ok()?.parse().ok()
+    }
+
+    /// The head to send the origin server for a forwarded request: the path
+    /// in origin form, without the proxy's own headers and asking the server
+    /// to close the connection after its response
+    pub fn forward_head(&self) -> Vec<u8> {
+        let authority = self.authority().unwrap_or_default();
+        let path = self
+            .target
+            .split_once("://")
+            .and_then(|(_, rest)| rest.find(['/', '?']).map(|i| &rest[i..]))
+            .unwrap_or("/");
+        let path = if path.starts_with('?') {
+            format!("/{}", path)
+        } else {
+            path.to_owned()
+        };
+
+        // headers the client listed in Connection are for this hop only too
+        let listed: Vec<&str> = self
+            .header("connection")
+            .map(|c| c.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+
+        // the URI decides the host, whatever Host the client sent
+        let mut head = format!(
+            "{} {} HTTP/1.{}\r\nHost: {}\r\n",
+            self.method, path, self.version, authority
+        )
+        .into_bytes();
+
+        for (name, value) in &self.headers {
+            let skip = |h: &&str| name.eq_ignore_ascii_case(h);
+            if name.eq_ignore_ascii_case("host")
+                || name.to_ascii_lowercase().starts_with("proxy-")
+                || HOP_BY_HOP.iter().any(skip)
+                || listed.iter().any(skip)
+            {
+                continue;
+            }
+            head.extend(name.as_bytes());
+            head.extend(b": ");
+            head.extend(value);
+            head.extend(b"\r\n");
+        }
+
+        head.extend(b"Connection: close\r\n\r\n");
+        head
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "Connection established",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        407 => "Proxy Authentication Required",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// A response from the proxy itself. Anything but a successful CONNECT
+/// closes the connection.
+pub fn response(status: u16) -> Vec<u8> {
+    let mut msg = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+
+    if status == 407 {
+        msg.push_str("Proxy-Authenticate: Basic realm=\"proxy\"\r\n");
+    }
+    if status != 200 {
+        msg.push_str("Content-Length: 0\r\nConnection: close\r\n");
+    }
+
+    msg.push_str("\r\n");
+    msg.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::io::Cursor;
+    use futures_util::stream::{self, TryStreamExt};
+
+    fn request(head: &str) -> HttpRequest {
+        HttpRequest::parse(head.as_bytes()).unwrap()
+    }
+
+    fn destination(head: &str) -> String {
+        request(head).destination().unwrap().to_string()
+    }
+
+    #[test]
+    fn destinations_of_absolute_uris() {
+        let dest = destination("GET http://example.com/a?b HTTP/1.1\r\n\r\n");
+        assert_eq!(dest, "example.com:80");
+
+        let dest = destination("GET http://user:pw@example.com:8080 HTTP/1.1\r\n\r\n");
+        assert_eq!(dest, "example.com:8080");
+
+        let dest = destination("GET http://[2001:db8::1]/ HTTP/1.1\r\n\r\n");
+        assert_eq!(dest, "[2001:db8::1]:80");
+
+        let dest = destination("GET HTTP://[::1]:8080/ HTTP/1.1\r\n\r\n");
+        assert_eq!(dest, "[::1]:8080");
+
+        for target in ["/relative", "https://example.com/", "http:///path"] {
+            let req = request(&format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert!(req.destination().is_err(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn destinations_of_connect_authorities() {
+        let dest = destination("CONNECT example.com:443 HTTP/1.1\r\n\r\n");
+        assert_eq!(dest, "example.com:443");
+
+        let dest = destination("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n");
+        assert_eq!(dest, "[2001:db8::1]:443");
+
+        // a tunnel has no default port
+        let req = request("CONNECT example.com HTTP/1.1\r\n\r\n");
+        assert!(req.destination().is_err());
+    }
+
+    #[test]
+    fn forwarded_heads_are_in_origin_form_without_proxy_headers() {
+        let req = request(
+            "GET http://example.com:8080/a/b?c=d HTTP/1.1\r\n\
+             Host: elsewhere\r\n\
+             Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\
+             Proxy-Connection: keep-alive\r\n\
+             Proxy-Custom: 1\r\n\
+             Connection: keep-alive, X-Hop\r\n\
+             Keep-Alive: timeout=5\r\n\
+             X-Hop: 1\r\n\
+             Accept: */*\r\n\r\n",
+        );
+
+        assert_eq!(
+            String::from_utf8(req.forward_head()).unwrap(),
+            "GET /a/b?c=d HTTP/1.1\r\n\
+             Host: example.com:8080\r\n\
+             Accept: */*\r\n\
+             Connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn forwarded_heads_always_have_a_path() {
+        let req = request("GET http://example.com HTTP/1.0\r\n\r\n");
+        assert!(req.forward_head().starts_with(b"GET / HTTP/1.0\r\n"));
+
+        let req = request("GET http://example.com?q HTTP/1.1\r\n\r\n");
+        assert!(req.forward_head().starts_with(b"GET /?q HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let with = |value: &str| {
+            request(&format!(
+                "GET http://example.com/ HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n",
+                value
+            ))
+            .credentials()
+        };
+
+        let user = with("Basic YWxpY2U6c2VjcmV0").unwrap();
+        assert_eq!(
+            (user.user.as_str(), user.pass.as_str()),
+            ("alice", "secret")
+        );
+        assert!(with("basic  YWxpY2U6c2VjcmV0 ").is_some());
+
+        // not base64, no colon, another scheme, no value
+        for value in [
+            "Basic !!!",
+            "Basic YWxpY2U=",
+            "Bearer YWxpY2U6c2VjcmV0",
+            "Basic",
+        ] {
+            assert!(with(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn body_framing() {
+        let body = |headers: &str| {
+            request(&format!(
+                "POST http://example.com/ HTTP/1.1\r\n{}\r\n",
+                headers
+            ))
+            .body()
+        };
+
+        assert_eq!(body("").unwrap(), RequestBody::Empty);
+        assert_eq!(body("Content-Length: 0\r\n").unwrap(), RequestBody::Empty);
+        assert_eq!(
+            body("Content-Length: 12\r\n").unwrap(),
+            RequestBody::Length(12)
+        );
+        assert_eq!(
+            body("Content-Length: 3, 3\r\nContent-Length: 3\r\n").unwrap(),
+            RequestBody::Length(3)
+        );
+        assert_eq!(
+            body("Transfer-Encoding: gzip, Chunked\r\n").unwrap(),
+            RequestBody::Chunked
+        );
+
+        for headers in [
+            "Content-Length: 3\r\nContent-Length: 4\r\n",
+            "Content-Length: -1\r\n",
+            "Content-Length: +3\r\n",
+            "Transfer-Encoding: chunked, gzip\r\n",
+            "Transfer-Encoding: chunked\r\nContent-Length: 3\r\n",
+        ] {
+            assert!(body(headers).is_err(), "{}", headers);
+        }
+    }
+
+    async fn copied(body: RequestBody, input: &[u8]) -> (Result<(), MyError>, Vec<u8>, Vec<u8>) {
+        let mut reader = Cursor::new(input.to_vec());
+        let mut out = Vec::new();
+        let result = copy_body(&mut reader, &mut out, body, &Throttle::default(), |_| {}).await;
+
+        let rest = input[reader.position() as usize..].to_vec();
+        (result, out, rest)
+    }
+
+    #[tokio::test]
+    async fn bodies_are_copied_up_to_their_end() {
+        let (result, out, rest) = copied(RequestBody::Length(5), b"helloGET").await;
+        assert!(result.is_ok());
+        assert_eq!(
+            (out.as_slice(), rest.as_slice()),
+            (&b"hello"[..], &b"GET"[..])
+        );
+
+        let chunked = b"5\r\nhello\r\nA;x=y\r\n0123456789\r\n0\r\nT: 1\r\n\r\n";
+        let (result, out, rest) =
+            copied(RequestBody::Chunked, &[&chunked[..], b"GET"].concat()).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            (out.as_slice(), rest.as_slice()),
+            (&chunked[..], &b"GET"[..])
+        );
+
+        let (result, _, _) = copied(RequestBody::Length(5), b"hel").await;
+        assert!(result.is_err());
+
+        for bad in [&b"x\r\n"[..], b"5\r\nhelloXX\r\n", b"5\r\nhel"] {
+            let (result, _, _) = copied(RequestBody::Chunked, bad).await;
+            assert!(result.is_err());
+        }
+    }
+
+    /// A reader handing out `pieces` one read at a time
+    fn in_pieces(pieces: &[&[u8]]) -> impl AsyncBufRead + Unpin {
+        let pieces: Vec<std::io::Result<Vec<u8>>> = pieces.iter().map(|p| Ok(p.to_vec())).collect();
+        stream::iter(pieces).into_async_read()
+    }
+
+    #[tokio::test]
+    async fn heads_are_read_across_reads() {
+        let mut reader = in_pieces(&[
+            b"GET http://a/ HT",
+            b"TP/1.1\r\nHost: a\r",
+            b"\n\r",
+            b"\nbody",
+        ]);
+        let head = read_head(&mut reader).await.unwrap();
+        assert_eq!(head, b"GET http://a/ HTTP/1.1\r\nHost: a\r\n\r\n");
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"body");
+    }
+
+    #[tokio::test]
+    async fn oversized_and_cut_off_heads_are_refused() {
+        let long = format!(
+            "GET http://a/ HTTP/1.1\r\nX: {}\r\n\r\n",
+            "x".repeat(MAX_HEAD)
+        );
+        let mut reader = in_pieces(&[long.as_bytes()]);
+        assert!(matches!(read_head(&mut reader).await, Err(MyError::Parse)));
+
+        let mut reader = in_pieces(&[b"GET http://a/ HTTP/1.1\r\n"]);
+        assert!(matches!(read_head(&mut reader).await, Err(MyError::IO)));
+    }
+}
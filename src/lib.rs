@@ -0,0 +1,34 @@
+//! A SOCKS4/4a/5 proxy that can run standalone or be embedded in a tokio
+//! application through [`ProxyServer`].
+
+pub mod acl;
+pub mod admin;
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod connector;
+pub mod connlimit;
+pub mod error;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod parse;
+pub mod passwd;
+pub mod proxy;
+pub mod ratelimit;
+pub mod resolver;
+pub mod router;
+pub mod server;
+pub mod socks;
+pub mod tls;
+pub mod udp;
+mod watch;
+
+pub use crate::auth::{AuthDenied, Authenticator, Identity};
+pub use crate::client::Client;
+pub use crate::config::Config;
+pub use crate::error::MyError;
+pub use crate::proxy::{ProxyHandle, ProxyServer, ShutdownSummary};
+pub use crate::server::{
+    Args, Peer, PeerInfo, Server, ServerConfig, Session, Timeouts, Traffic, User,
+};
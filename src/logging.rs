@@ -0,0 +1,273 @@
+use crate::auth::Identity;
+use crate::error::MyError;
+use crate::socks::{Cmd, Destination};
+use clap::ArgEnum;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, FilterExt};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+/// Target of the access log events, one per finished connection
+pub const ACCESS_TARGET: &str = "access";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ArgEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
+/// How often a log file is rotated
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ArgEnum)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for Rotation {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
+impl From<Rotation> for rolling::Rotation {
+    fn from(r: Rotation) -> Self {
+        match r {
+            Rotation::Never => rolling::Rotation::NEVER,
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+        }
+    }
+}
+
+/// Where log lines go and how they look
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LogOutput {
+    pub format: LogFormat,
+    /// Written to stderr when not set. Rotated files get the date appended.
+    pub file: Option<PathBuf>,
+    pub rotation: Rotation,
+}
+
+impl Default for LogOutput {
+    fn default() -> Self {
+        LogOutput {
+            format: LogFormat::Text,
+            file: None,
+            rotation: Rotation::Never,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LogConfig {
+    /// Filter in `RUST_LOG` syntax, e.g. `info` or `warn,access=info`
+    pub level: String,
+    pub output: LogOutput,
+    /// A separate output for the access log. When not set access lines go
+    /// to the main output, subject to `level`.
+    pub access: Option<LogOutput>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            output: LogOutput::default(),
+            access: None,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Install the global subscriber. Lines are written by a background
+    /// thread, keep the returned guard alive until exit so they are flushed.
+    pub fn init(&self) -> Result<LogGuard, MyError> {
+        let filter = EnvFilter::try_new(&self.level).map_err(|_| MyError::Parse)?;
+        let mut guards = Vec::new();
+        let mut layers = Vec::new();
+
+        match &self.access {
+            Some(access) => {
+                let main = filter.and(filter_fn(|meta| meta.target() != ACCESS_TARGET));
+                layers.push(layer(&self.output, &mut guards)?.with_filter(main).boxed());
+
+                let access_only = filter_fn(|meta| {
+                    meta.target() == ACCESS_TARGET && *meta.level() <= Level::INFO
+                });
+                layers.push(layer(access, &mut guards)?.with_filter(access_only).boxed());
+            }
+            None => layers.push(
+                layer(&self.output, &mut guards)?
+                    .with_filter(filter)
+                    .boxed(),
+            ),
+        }
+
+        tracing_subscriber::registry()
+            .with(layers)
+            .try_init()
+            .map_err(|_| MyError::Unknown)?;
+
+        Ok(LogGuard { _guards: guards })
+    }
+}
+
+/// Flushes buffered log lines when dropped
+#[derive(Debug)]
+pub struct LogGuard {
+    _guards: Vec<WorkerGuard>,
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn layer(output: &LogOutput, guards: &mut Vec<WorkerGuard>) -> Result<BoxedLayer, MyError> {
+    let (writer, guard) = match &output.file {
+        Some(path) => {
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            let name = path.file_name().ok_or(MyError::Parse)?;
+
+            let appender = RollingFileAppender::builder()
+                .rotation(output.rotation.into())
+                .filename_prefix(name.to_string_lossy())
+                .build(dir)
+                .map_err(|_| MyError::IO)?;
+
+            tracing_appender::non_blocking(appender)
+        }
+        None => tracing_appender::non_blocking(std::io::stderr()),
+    };
+    guards.push(guard);
+
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+
+    Ok(match output.format {
+        LogFormat::Text => fmt.with_ansi(output.file.is_none()).boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    })
+}
+
+/// How a connection ended
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    Ok,
+    AuthFailed,
+    /// Refused by an access rule or a reject route
+    Denied,
+    ConnectFailed,
+    /// A protocol or I/O error on the client side
+    Error,
+    /// Ended by an operator through the admin API
+    Terminated,
+    /// Turned away by a connection limit
+    Limited,
+    /// Closed by the idle timeout or the session duration limit
+    Timeout,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Ok => "ok",
+            Outcome::AuthFailed => "auth_failed",
+            Outcome::Denied => "denied",
+            Outcome::ConnectFailed => "connect_failed",
+            Outcome::Error => "error",
+            Outcome::Terminated => "terminated",
+            Outcome::Limited => "limited",
+            Outcome::Timeout => "timeout",
+        })
+    }
+}
+
+/// What is known about a connection, written to the access log when it ends
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    pub client: Option<SocketAddr>,
+    pub started: Instant,
+    /// `socks4` or `socks5`, not set until the client's greeting is parsed
+    pub proto: Option<&'static str>,
+    pub user: Option<Identity>,
+    pub cmd: Option<Cmd>,
+    pub dest: Option<Destination>,
+    /// The routing decision, see `route_label`, once the request got that
+    /// far
+    pub route: Option<String>,
+    /// The address the outbound connection reached, which shows what a
+    /// domain resolved to
+    pub remote: Option<SocketAddr>,
+    /// Bytes from the client to the destination
+    pub sent: u64,
+    /// Bytes from the destination to the client
+    pub received: u64,
+    /// Left unset while nothing has gone wrong
+    pub outcome: Option<Outcome>,
+}
+
+impl AccessRecord {
+    pub fn new(client: Option<SocketAddr>) -> Self {
+        AccessRecord {
+            client,
+            started: Instant::now(),
+            proto: None,
+            user: None,
+            cmd: None,
+            dest: None,
+            route: None,
+            remote: None,
+            sent: 0,
+            received: 0,
+            outcome: None,
+        }
+    }
+
+    /// Write the access log line. Events are emitted without a parent span
+    /// so each line stands on its own.
+    pub fn log(&self) {
+        tracing::info!(
+            target: ACCESS_TARGET,
+            parent: None,
+            client = %self.client.map_or_else(|| "-".to_owned(), |c| c.to_string()),
+            user = %self.user.as_ref().map_or_else(|| "-".to_owned(), |u| u.to_string()),
+            proto = self.proto.unwrap_or("-"),
+            cmd = %self.cmd.map_or_else(|| "-".to_owned(), |c| c.to_string()),
+            dest = %self.dest.as_ref().map_or_else(|| "-".to_owned(), Destination::to_string),
+            route = self.route.as_deref().unwrap_or("-"),
+            remote = %self.remote.map_or_else(|| "-".to_owned(), |r| r.to_string()),
+            sent = self.sent,
+            received = self.received,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            outcome = %self.outcome.unwrap_or(Outcome::Ok),
+        );
+    }
+}
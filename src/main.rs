@@ -1,59 +1,156 @@
-#![feature(io_error_more)]
-#![feature(io_error_uncategorized)]
-
-mod client;
-mod error;
-mod parse;
-mod server;
-mod socks;
-
-use crate::client::Client;
-use crate::error::MyError;
-use crate::server::Args;
-use crate::server::{Message, Server, Session};
 use clap::Parser;
-use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use socks_proxy_server::admin::Admin;
+use socks_proxy_server::config::ConfigError;
+use socks_proxy_server::metrics::Metrics;
+use socks_proxy_server::passwd::HashedPassword;
+use socks_proxy_server::server::Command;
+use socks_proxy_server::{Args, Config};
+use std::io::BufRead;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
 
 #[tokio::main]
 async fn main() {
-    let args: Args = Args::parse();
+    let mut args: Args = Args::parse();
 
-    dbg!(&args);
+    if let Some(command) = args.command.take() {
+        run_command(command);
+        return;
+    }
 
-    let socks4 = args.socks4;
-    let socks5 = args.socks5;
+    let config = Config::from_args(&args).unwrap_or_else(|errors| report(&errors));
 
-    let socket = SocketAddr::new(args.ip, args.port);
+    let _guard = config.log.init().expect("Unable to set up logging");
 
-    let listener = TcpListener::bind(socket)
-        .await
-        .expect("Unable to bind to socket");
-
-    let mut server = Server::new(args);
-
-    let s = server.send.clone();
-
-    tokio::spawn(async move {
-        server.run().await;
-    });
-
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let send = s.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = Client::new(stream, send)
-                        .handle_connection(socks4, socks5)
-                        .await
-                    {
-                        dbg!("{}", e);
-                    }
-                });
+    debug!(?args);
+
+    let metrics = Metrics::new();
+    let mut proxies = Vec::new();
+
+    for proxy in config.proxies().expect("Invalid configuration") {
+        let proxy = proxy
+            .metrics(metrics.clone())
+            .start()
+            .await
+            .expect("Unable to bind to socket");
+        info!(addr = %proxy.local_addr(), "listening");
+        proxies.push(proxy);
+    }
+
+    // stops the metrics and admin endpoints
+    let stop = CancellationToken::new();
+
+    if let Some(addr) = config.metrics {
+        info!(%addr, "serving metrics");
+        let stopped = stop.clone().cancelled_owned();
+        tokio::spawn(async move {
+            if let Err(e) = metrics.serve(addr, stopped).await {
+                error!(%addr, error = %e, "metrics endpoint failed");
+            }
+        });
+    }
+
+    if let Some(addr) = config.admin {
+        info!(%addr, "serving admin API");
+        let admin = Admin::new(proxies.iter().map(|p| p.server().clone()).collect());
+        let stopped = stop.clone().cancelled_owned();
+        tokio::spawn(async move {
+            if let Err(e) = admin.serve(&addr, stopped).await {
+                error!(%addr, error = %e, "admin API failed");
+            }
+        });
+    }
+
+    shutdown_signal().await;
+    info!("shutting down, draining open connections");
+
+    for proxy in &proxies {
+        proxy.shutdown();
+    }
+
+    for proxy in proxies {
+        let addr = proxy.local_addr();
+
+        match proxy.await {
+            Ok(summary) => {
+                info!(
+                    %addr,
+                    drained = summary.drained,
+                    closed = summary.closed,
+                    "stopped"
+                );
+                for session in summary.closed_sessions {
+                    info!(
+                        client = %session.client2server,
+                        dest = %session.destination,
+                        user = %session
+                            .user
+                            .map_or_else(|| "-".to_owned(), |id| id.to_string()),
+                        sent = session.traffic.sent(),
+                        received = session.traffic.received(),
+                        idle_ms = session.traffic.idle().as_millis() as u64,
+                        "closed open session"
+                    );
+                }
             }
             Err(e) => {
-                println!("couldn't connect {}", e);
+                error!(%addr, error = %e, "proxy failed");
             }
         }
     }
+
+    stop.cancel();
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Unable to listen for ctrl-c");
+}
+
+fn report(errors: &[ConfigError]) -> ! {
+    for e in errors {
+        eprintln!("{}", e);
+    }
+    std::process::exit(1);
+}
+
+fn run_command(command: Command) {
+    match command {
+        Command::Hash { user, algorithm } => {
+            if user.is_empty() || user.contains(':') {
+                eprintln!("user must be non-empty and can't contain ':'");
+                std::process::exit(2);
+            }
+
+            let mut pass = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut pass)
+                .expect("Unable to read password");
+
+            let pass = pass.trim_end_matches(&['\r', '\n'][..]);
+            let hash = HashedPassword::new(pass, algorithm).expect("Unable to hash password");
+
+            println!("{}:{}", user, hash);
+        }
+        Command::CheckConfig { path } => match Config::load(&path) {
+            Ok(_) => println!("{}: ok", path.display()),
+            Err(errors) => report(&errors),
+        },
+    }
 }
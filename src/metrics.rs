@@ -0,0 +1,284 @@
+use crate::error::MyError;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Latency buckets in seconds, from a local connect up to the default
+/// connect timeout
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 120.0,
+];
+
+/// Prometheus metrics for every listener of a process. Clones share the
+/// same registry, so one endpoint can serve all listeners.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    accepted: IntCounterVec,
+    rejected: IntCounterVec,
+    active_sessions: IntGaugeVec,
+    handshakes: IntCounterVec,
+    auth_failures: IntCounterVec,
+    replies: IntCounterVec,
+    bytes: IntCounterVec,
+    handshake_seconds: HistogramVec,
+    connect_seconds: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+    let histogram = HistogramVec::new(opts, labels).unwrap();
+    registry.register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        // names and labels are fixed, so registration can only fail on a bug
+        let registry = Registry::new_custom(Some("socks".to_owned()), None).unwrap();
+
+        let active_sessions = IntGaugeVec::new(
+            Opts::new("active_sessions", "Sessions currently relaying"),
+            &["listener"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(active_sessions.clone()))
+            .unwrap();
+
+        Metrics {
+            accepted: counter(
+                &registry,
+                "connections_accepted_total",
+                "Connections accepted",
+                &["listener"],
+            ),
+            rejected: counter(
+                &registry,
+                "connections_rejected_total",
+                "Connections turned away by a connection limit, by scope",
+                &["listener", "scope"],
+            ),
+            active_sessions,
+            handshakes: counter(
+                &registry,
+                "handshakes_total",
+                "Finished handshakes by protocol and outcome",
+                &["listener", "proto", "outcome"],
+            ),
+            auth_failures: counter(
+                &registry,
+                "auth_failures_total",
+                "Rejected username/password attempts",
+                &["listener"],
+            ),
+            replies: counter(
+                &registry,
+                "replies_total",
+                "Replies sent to clients by protocol and reply code",
+                &["listener", "proto", "code"],
+            ),
+            bytes: counter(
+                &registry,
+                "bytes_total",
+                "Bytes relayed, sent is from the client and received is to it",
+                &["listener", "user", "direction"],
+            ),
+            handshake_seconds: histogram(
+                &registry,
+                "handshake_duration_seconds",
+                "Time from accepting a connection to the final handshake reply",
+                &["listener", "proto"],
+            ),
+            connect_seconds: histogram(
+                &registry,
+                "connect_duration_seconds",
+                "Time taken to open outbound connections, by outbound",
+                &["listener", "outbound"],
+            ),
+            registry,
+        }
+    }
+
+    /// The metrics of one listener
+    pub fn listener(&self, addr: SocketAddr) -> ListenerMetrics {
+        ListenerMetrics {
+            metrics: self.clone(),
+            listener: addr.to_string(),
+        }
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // writing to a Vec can't fail
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        buf
+    }
+
+    /// Serve `GET /metrics` on `addr` until `shutdown` resolves
+    pub async fn serve(
+        self,
+        addr: SocketAddr,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), MyError> {
+        let make_service = make_service_fn(move |_| {
+            let metrics = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let metrics = metrics.clone();
+                    async move { Ok::<_, Infallible>(metrics.respond(req)) }
+                }))
+            }
+        });
+
+        hyper::Server::try_bind(&addr)
+            .map_err(|_| MyError::IO)?
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown)
+            .await
+            .map_err(|_| MyError::IO)
+    }
+
+    fn respond(&self, req: Request<Body>) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+
+        if req.uri().path() != "/metrics" {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        } else if req.method() != Method::GET {
+            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        } else {
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            *response.body_mut() = Body::from(self.encode());
+        }
+
+        response
+    }
+}
+
+/// Metrics with the listener label filled in
+#[derive(Debug, Clone)]
+pub struct ListenerMetrics {
+    metrics: Metrics,
+    listener: String,
+}
+
+impl ListenerMetrics {
+    pub fn accepted(&self) {
+        self.metrics
+            .accepted
+            .with_label_values(&[&self.listener])
+            .inc();
+    }
+
+    pub fn rejected(&self, scope: &str) {
+        self.metrics
+            .rejected
+            .with_label_values(&[&self.listener, scope])
+            .inc();
+    }
+
+    pub fn session_started(&self) {
+        self.metrics
+            .active_sessions
+            .with_label_values(&[&self.listener])
+            .inc();
+    }
+
+    pub fn session_ended(&self) {
+        self.metrics
+            .active_sessions
+            .with_label_values(&[&self.listener])
+            .dec();
+    }
+
+    pub fn handshake(&self, proto: &str, outcome: &str, took: Duration) {
+        self.metrics
+            .handshakes
+            .with_label_values(&[&self.listener, proto, outcome])
+            .inc();
+        self.metrics
+            .handshake_seconds
+            .with_label_values(&[&self.listener, proto])
+            .observe(took.as_secs_f64());
+    }
+
+    pub fn auth_failure(&self) {
+        self.metrics
+            .auth_failures
+            .with_label_values(&[&self.listener])
+            .inc();
+    }
+
+    pub fn reply(&self, proto: &str, code: u16) {
+        self.metrics
+            .replies
+            .with_label_values(&[&self.listener, proto, &code.to_string()])
+            .inc();
+    }
+
+    /// The byte counters of `user`, `-` for anonymous clients, to add to
+    /// as data is relayed
+    pub fn bytes(&self, user: &str) -> ByteCounters {
+        let counter = |direction| {
+            self.metrics
+                .bytes
+                .with_label_values(&[&self.listener, user, direction])
+        };
+
+        ByteCounters {
+            sent: counter("sent"),
+            received: counter("received"),
+        }
+    }
+
+    pub fn connect(&self, outbound: &str, took: Duration) {
+        self.metrics
+            .connect_seconds
+            .with_label_values(&[&self.listener, outbound])
+            .observe(took.as_secs_f64());
+    }
+}
+
+/// Relayed bytes of one user on one listener
+#[derive(Debug, Clone)]
+pub struct ByteCounters {
+    sent: IntCounter,
+    received: IntCounter,
+}
+
+impl ByteCounters {
+    /// Count bytes from the client
+    pub fn sent(&self, n: u64) {
+        self.sent.inc_by(n);
+    }
+
+    /// Count bytes to the client
+    pub fn received(&self, n: u64) {
+        self.received.inc_by(n);
+    }
+}
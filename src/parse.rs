@@ -15,7 +15,7 @@ use crate::error::MyError;
 use crate::socks::Address::IP;
 use crate::socks::{
     Address, Destination, SOCKS4Cmd, SOCKS4Init, SOCKS5AuthMethod, SOCKS5AuthRequest, SOCKS5Cmd,
-    SOCKS5ConnectRequest, SOCKS5Init, SOCKSInit, SOCKS,
+    SOCKS5ConnectRequest, SOCKS5Init, SOCKS5UdpHeader, SOCKSInit, SOCKS,
 };
 
 fn take_until_null_consume(i: &[u8]) -> IResult<&[u8], &[u8], MyError> {
@@ -215,3 +215,55 @@ pub fn socks5_connection_request(input: &[u8]) -> IResult<&[u8], SOCKS5ConnectRe
 
     Ok((remaining, SOCKS5ConnectRequest { cmd, dest }))
 }
+
+pub fn socks5_udp_header(input: &[u8]) -> IResult<&[u8], SOCKS5UdpHeader, MyError> {
+    let (remaining, (_, _, frag, dest)) =
+        tuple((socks5_rsv, socks5_rsv, number_u8, socks5_dst))(input)?;
+
+    Ok((remaining, SOCKS5UdpHeader { frag, dest }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    /// Encode `dest` as a SOCKS5 address and parse it back
+    fn round_trip(dest: &str) -> Destination {
+        let dest: Destination = dest.parse().unwrap();
+        let mut buf = BytesMut::new();
+        dest.put_socks5(&mut buf).unwrap();
+
+        let (remaining, parsed) = socks5_dst(&buf).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(parsed, dest);
+        parsed
+    }
+
+    #[test]
+    fn ipv4_addresses_round_trip() {
+        let dest = round_trip("192.0.2.7:8080");
+        assert_eq!(dest.ipv4_slice(), Some([192, 0, 2, 7]));
+    }
+
+    #[test]
+    fn ipv6_addresses_round_trip() {
+        let dest = round_trip("[2001:db8::1]:443");
+        assert_eq!(dest.ipv6_slice().unwrap()[..2], [0x20, 0x01]);
+        round_trip("[::ffff:192.0.2.7]:80");
+    }
+
+    #[test]
+    fn domain_names_round_trip() {
+        round_trip("example.com:443");
+
+        let longest = format!("{}:1", "a".repeat(255));
+        round_trip(&longest);
+
+        let too_long = Destination {
+            addr: Address::Name("a".repeat(256)),
+            port: 1,
+        };
+        assert!(too_long.put_socks5(&mut BytesMut::new()).is_err());
+    }
+}
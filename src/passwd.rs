@@ -0,0 +1,183 @@
+use crate::error::MyError;
+use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ArgEnum;
+use rand_core::OsRng;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum HashAlgorithm {
+    Argon2,
+    Bcrypt,
+}
+
+/// A password as stored in a users file. Besides argon2 PHC strings this
+/// understands the formats written by `htpasswd -B` and `htpasswd -s`, and
+/// plaintext marked with `{PLAIN}`. Anything else, including the MD5 and
+/// SHA-crypt hashes htpasswd can also write, is rejected rather than taken
+/// for a password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashedPassword {
+    Argon2(String),
+    Bcrypt(String),
+    Sha1([u8; 20]),
+    Plain(String),
+}
+
+impl HashedPassword {
+    pub fn new(pass: &str, algorithm: HashAlgorithm) -> Result<Self, MyError> {
+        match algorithm {
+            HashAlgorithm::Argon2 => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::default()
+                    .hash_password(pass.as_bytes(), &salt)
+                    .map_err(|_| MyError::Unknown)?;
+                Ok(HashedPassword::Argon2(hash.to_string()))
+            }
+            HashAlgorithm::Bcrypt => {
+                let hash =
+                    bcrypt::hash(pass, bcrypt::DEFAULT_COST).map_err(|_| MyError::Unknown)?;
+                Ok(HashedPassword::Bcrypt(hash))
+            }
+        }
+    }
+
+    /// Whether checking this password is expensive enough that it should be
+    /// kept off the async runtime
+    pub fn is_slow(&self) -> bool {
+        matches!(self, HashedPassword::Argon2(_) | HashedPassword::Bcrypt(_))
+    }
+
+    pub fn verify(&self, pass: &str) -> bool {
+        match self {
+            HashedPassword::Argon2(hash) => match argon2::PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(pass.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            },
+            HashedPassword::Bcrypt(hash) => bcrypt::verify(pass, hash).unwrap_or(false),
+            HashedPassword::Sha1(digest) => {
+                constant_time_eq(&Sha1::digest(pass.as_bytes()), digest)
+            }
+            HashedPassword::Plain(plain) => constant_time_eq(pass.as_bytes(), plain.as_bytes()),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl FromStr for HashedPassword {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("$argon2") {
+            argon2::PasswordHash::new(s).map_err(|_| MyError::Parse)?;
+            Ok(HashedPassword::Argon2(s.to_owned()))
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| s.starts_with(prefix))
+        {
+            if s.len() != 60 {
+                return Err(MyError::Parse);
+            }
+            Ok(HashedPassword::Bcrypt(s.to_owned()))
+        } else if let Some(digest) = s.strip_prefix("{SHA}") {
+            let digest = STANDARD.decode(digest).map_err(|_| MyError::Parse)?;
+            Ok(HashedPassword::Sha1(
+                digest.try_into().map_err(|_| MyError::Parse)?,
+            ))
+        } else if let Some(plain) = s.strip_prefix("{PLAIN}") {
+            Ok(HashedPassword::Plain(plain.to_owned()))
+        } else {
+            Err(MyError::Parse)
+        }
+    }
+}
+
+impl fmt::Display for HashedPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashedPassword::Argon2(hash) | HashedPassword::Bcrypt(hash) => f.write_str(hash),
+            HashedPassword::Sha1(digest) => write!(f, "{{SHA}}{}", STANDARD.encode(digest)),
+            HashedPassword::Plain(plain) => write!(f, "{{PLAIN}}{}", plain),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<HashedPassword, MyError> {
+        s.parse()
+    }
+
+    #[test]
+    fn argon2() {
+        let hash = HashedPassword::new("secret", HashAlgorithm::Argon2).unwrap();
+        let parsed = parse(&hash.to_string()).unwrap();
+
+        assert!(matches!(parsed, HashedPassword::Argon2(_)));
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("wrong"));
+    }
+
+    #[test]
+    fn bcrypt() {
+        // the lowest cost, the default takes seconds in debug builds
+        let parsed = parse(&bcrypt::hash("secret", 4).unwrap()).unwrap();
+
+        assert!(matches!(parsed, HashedPassword::Bcrypt(_)));
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("wrong"));
+        assert!(parse("$2y$05$short").is_err());
+    }
+
+    #[test]
+    fn sha1() {
+        // htpasswd -nbs alice secret
+        let parsed = parse("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=").unwrap();
+
+        assert!(matches!(parsed, HashedPassword::Sha1(_)));
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("wrong"));
+        assert_eq!(parsed.to_string(), "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=");
+        assert!(parse("{SHA}dG9vIHNob3J0").is_err());
+    }
+
+    #[test]
+    fn marked_plaintext() {
+        let parsed = parse("{PLAIN}secret").unwrap();
+
+        assert_eq!(parsed, HashedPassword::Plain("secret".to_owned()));
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("{PLAIN}secret"));
+        assert_eq!(parsed.to_string(), "{PLAIN}secret");
+    }
+
+    #[test]
+    fn unmarked_plaintext_is_rejected() {
+        assert!(parse("secret").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn unsupported_hashes_are_rejected() {
+        for hash in [
+            // htpasswd -nbm alice secret
+            "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/",
+            "$1$saltsalt$qjXMvbEw8oaL.CzflDugX/",
+            "$5$saltsalt$w0Xxy1QZ0fvjSxasXiN.FnMHyPnsRTedZNgqw0eKXmD",
+            "$6$saltsalt$qFmFH.bQmmtXzyBY0s9v7Oicd2z4XSIecDzlB5KiA2/jctKu9YterLp8wwnSq.qc.eoxqOmSuNp2xS0ktL3nh/",
+        ] {
+            assert!(parse(hash).is_err(), "{}", hash);
+        }
+    }
+}
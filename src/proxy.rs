@@ -0,0 +1,778 @@
+use crate::acl::Acl;
+use crate::auth::{Authenticator, StaticUsers};
+use crate::client::{Client, Protocol, Protocols};
+use crate::connector::{Connector, Direct};
+use crate::connlimit::{ConnLimiter, ConnLimits, ConnPermit};
+use crate::error::MyError;
+use crate::logging::{AccessRecord, Outcome};
+use crate::metrics::Metrics;
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::resolver::Resolver;
+use crate::router::Router;
+use crate::server::{Peer, PeerInfo, Server, ServerConfig, Session, Timeouts, User};
+use crate::tls::CertIdentity;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::ServerConfig as TlsConfig;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument};
+
+/// Builder for a proxy listener. Nothing is bound until `start` is called.
+#[derive(Debug, Clone)]
+pub struct ProxyServer {
+    addr: SocketAddr,
+    protocols: Protocols,
+    tls: Option<Arc<TlsConfig>>,
+    plaintext: bool,
+    client_identity: CertIdentity,
+    config: ServerConfig,
+}
+
+impl Default for ProxyServer {
+    fn default() -> Self {
+        ProxyServer {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080),
+            protocols: Protocols {
+                socks5: true,
+                ..Protocols::default()
+            },
+            tls: None,
+            plaintext: false,
+            client_identity: CertIdentity::default(),
+            config: ServerConfig::default(),
+        }
+    }
+}
+
+impl ProxyServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Address to listen on, use port 0 to let the OS pick one
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn socks4(mut self, enable: bool) -> Self {
+        self.protocols.socks4 = enable;
+        self
+    }
+
+    pub fn socks5(mut self, enable: bool) -> Self {
+        self.protocols.socks5 = enable;
+        self
+    }
+
+    /// Serve HTTP CONNECT and forward proxy requests, with the same users
+    /// checked through `Proxy-Authorization: Basic`. Clients of every enabled
+    /// protocol share the port.
+    pub fn http(mut self, enable: bool) -> Self {
+        self.protocols.http = enable;
+        self
+    }
+
+    /// Terminate TLS, serving the enabled protocols inside it. Clients that
+    /// don't start a TLS handshake are closed
+    pub fn tls(mut self, config: Arc<TlsConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Also serve clients of a TLS listener that don't start a TLS
+    /// handshake, in plaintext. Anything they send, passwords included,
+    /// crosses the network unencrypted.
+    pub fn plaintext(mut self, enable: bool) -> Self {
+        self.plaintext = enable;
+        self
+    }
+
+    /// Which field of a verified client certificate names the user, when
+    /// the TLS settings ask clients for one
+    pub fn client_identity(mut self, field: CertIdentity) -> Self {
+        self.client_identity = field;
+        self
+    }
+
+    /// Require SOCKS5 username/password authentication against `users`
+    pub fn users(self, users: Vec<User>) -> Self {
+        self.authenticator(StaticUsers::new(users))
+    }
+
+    /// Require SOCKS5 username/password authentication checked by `authenticator`
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.config.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Replace all policy settings at once
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.config.timeouts = timeouts;
+        self
+    }
+
+    /// Open outbound connections with `connector` instead of dialing directly
+    pub fn connector(mut self, connector: impl Connector + 'static) -> Self {
+        self.config.connector = Arc::new(connector);
+        self
+    }
+
+    /// Look up domains with `resolver`, for access rules, UDP and direct
+    /// connections. Replaces the connector, so set that afterwards
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.config.connector = Arc::new(Direct::new(resolver.clone()));
+        self.config.resolver = resolver;
+        self
+    }
+
+    /// Routes picking an outbound per destination
+    pub fn router(mut self, router: Router) -> Self {
+        self.config.router = router;
+        self
+    }
+
+    /// Report to `metrics`, to share one registry between listeners
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Bandwidth limits. Global, user and IP buckets are only shared with
+    /// listeners given the same `ServerConfig`
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.config.rate_limiter = RateLimiter::new(limits);
+        self
+    }
+
+    /// Caps on concurrent connections. Global, user and IP counts are only
+    /// shared with listeners given the same `ServerConfig`
+    pub fn conn_limits(mut self, limits: ConnLimits) -> Self {
+        self.config.conn_limiter = ConnLimiter::new(limits);
+        self
+    }
+
+    /// Rules deciding which destinations clients may reach
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.config.acl = acl;
+        self
+    }
+
+    /// Bind the listener and start accepting clients in the background.
+    pub async fn start(self) -> Result<ProxyHandle, MyError> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let server = Arc::new(Server::new(self.config, local_addr));
+        let shutdown = CancellationToken::new();
+
+        let serving = Serving {
+            protocols: self.protocols,
+            tls: self.tls.map(TlsAcceptor::from),
+            plaintext: self.plaintext,
+            client_identity: self.client_identity,
+        };
+        let task = tokio::spawn(accept_loop(
+            listener,
+            server.clone(),
+            serving,
+            shutdown.clone(),
+        ));
+
+        Ok(ProxyHandle {
+            local_addr,
+            server,
+            shutdown,
+            task,
+        })
+    }
+}
+
+/// What was left when a proxy shut down
+#[derive(Debug, Clone)]
+pub struct ShutdownSummary {
+    /// Connections that finished on their own while draining
+    pub drained: usize,
+    /// Connections still open at the drain deadline, which were closed
+    pub closed: usize,
+    /// The relaying sessions among those that were closed
+    pub closed_sessions: Vec<Session>,
+}
+
+// how long to wait after a failed accept, doubling while it keeps failing
+pub(crate) const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+pub(crate) const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// time terminated sessions get to log and leave the registry at the drain
+// deadline, before connections still in their handshake are dropped
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// How a listener serves the clients it accepts
+#[derive(Clone)]
+struct Serving {
+    protocols: Protocols,
+    tls: Option<TlsAcceptor>,
+    plaintext: bool,
+    client_identity: CertIdentity,
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    server: Arc<Server>,
+    serving: Serving,
+    stopped: CancellationToken,
+) -> ShutdownSummary {
+    let mut clients = JoinSet::new();
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+
+    loop {
+        tokio::select! {
+            _ = stopped.cancelled() => break,
+            // reap finished clients so the set doesn't grow forever
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    server.metrics().accepted();
+
+                    // over a limit the socket is closed before any
+                    // handshake, so it holds nothing while others wait
+                    let permit = match server.admit(peer.ip()) {
+                        Ok(permit) => permit,
+                        Err(scope) => {
+                            info!(client = %peer, %scope, "connection limit reached, closing");
+                            let mut access = AccessRecord::new(Some(peer));
+                            access.outcome = Some(Outcome::Limited);
+                            access.log();
+                            continue;
+                        }
+                    };
+
+                    let server = server.clone();
+                    let serving = serving.clone();
+                    let span = info_span!(
+                        "conn",
+                        client = %peer,
+                        user = field::Empty,
+                        proto = field::Empty,
+                        cmd = field::Empty,
+                        dest = field::Empty,
+                        route = field::Empty,
+                    );
+
+                    clients.spawn(
+                        serve(stream, server, permit, serving).instrument(span),
+                    );
+                }
+                Err(e) => {
+                    // out of file descriptors and the like won't clear up
+                    // by retrying at once
+                    warn!(
+                        error = %e,
+                        retry_ms = backoff.as_millis() as u64,
+                        "couldn't accept connection"
+                    );
+
+                    tokio::select! {
+                        _ = stopped.cancelled() => break,
+                        _ = sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+            },
+        }
+    }
+
+    drop(listener);
+
+    let open = clients.len();
+    let deadline = sleep(server.timeouts.drain);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            finished = clients.join_next() => if finished.is_none() {
+                break;
+            },
+            _ = &mut deadline => break,
+        }
+    }
+
+    let closed = clients.len();
+    let closed_sessions = server.active_sessions();
+
+    // terminated sessions end like any other, with their access log line
+    // and metrics, so they are waited for rather than dropped
+    server.terminate(|_| true);
+    let _ = timeout(CLOSE_GRACE, async {
+        while clients.join_next().await.is_some() {}
+    })
+    .await;
+
+    // dropping the rest closes both sides of their connections
+    clients.shutdown().await;
+
+    ShutdownSummary {
+        drained: open - closed,
+        closed,
+        closed_sessions,
+    }
+}
+
+/// Serve an accepted client, after the TLS handshake on a TLS listener
+/// unless it allows plaintext and the client doesn't start one
+async fn serve(stream: TcpStream, server: Arc<Server>, permit: ConnPermit, serving: Serving) {
+    let peer = match stream.peer() {
+        Ok(peer) => peer,
+        Err(e) => {
+            debug!(error = %e, "connection closed before it was served");
+            return;
+        }
+    };
+
+    let Some(acceptor) = serving.tls.clone() else {
+        let client = Client::new(stream, peer, server);
+        return run_client(client, permit, serving.protocols).await;
+    };
+
+    let mut first = [0u8];
+    match timeout(server.timeouts.init, stream.peek(&mut first)).await {
+        Ok(Ok(0)) => return debug!("closed before sending anything"),
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => return debug!(error = %e, "connection closed before it was served"),
+        Err(_) => return debug!("timed out waiting for the first byte"),
+    }
+
+    if Protocol::detect(first[0]) != Some(Protocol::Tls) {
+        if !serving.plaintext {
+            return info!(
+                first_byte = first[0],
+                "client didn't start a TLS handshake, closing"
+            );
+        }
+
+        let client = Client::new(stream, peer, server);
+        return run_client(client, permit, serving.protocols).await;
+    }
+
+    serve_tls(stream, peer, acceptor, server, permit, serving).await
+}
+
+/// Serve a client that started a TLS handshake, as the user its certificate
+/// names when it presented one
+async fn serve_tls<S>(
+    stream: S,
+    peer: Peer,
+    acceptor: TlsAcceptor,
+    server: Arc<Server>,
+    permit: ConnPermit,
+    serving: Serving,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let stream = match timeout(server.timeouts.init, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return debug!(error = %e, "TLS handshake failed"),
+        Err(_) => return debug!("TLS handshake timed out"),
+    };
+
+    // only present once verified against the client CA
+    let cert = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|c| c.first());
+    let identity = match cert.map(|cert| serving.client_identity.of(cert)) {
+        Some(Some(identity)) => Some(identity),
+        Some(None) => {
+            let field = serving.client_identity;
+            return info!(%field, "client certificate doesn't name a user, closing");
+        }
+        None => None,
+    };
+
+    let mut client = Client::new(stream, peer, server);
+    if let Some(identity) = identity {
+        client.certified(identity);
+    }
+    run_client(client, permit, serving.protocols).await
+}
+
+async fn run_client<S>(mut client: Client<S>, permit: ConnPermit, protocols: Protocols)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    client.admitted(permit);
+
+    if let Err(e) = client.handle_connection(protocols).await {
+        debug!(error = %e, "connection ended with an error");
+    }
+}
+
+/// A running proxy. Awaiting it waits until the listener stops and open
+/// connections have drained. Dropping it leaves the proxy running in the
+/// background, like dropping a `JoinHandle`; only `shutdown` stops it.
+#[derive(Debug)]
+pub struct ProxyHandle {
+    local_addr: SocketAddr,
+    server: Arc<Server>,
+    shutdown: CancellationToken,
+    task: JoinHandle<ShutdownSummary>,
+}
+
+impl ProxyHandle {
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    /// Stop accepting new clients. Open connections get the drain timeout
+    /// to finish, then the rest are closed.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl Future for ProxyHandle {
+    type Output = Result<ShutdownSummary, MyError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task)
+            .poll(cx)
+            .map(|r| r.map_err(|_| MyError::Unknown))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{client_verifier, CertificateFiles};
+    use rustls_pemfile::{certs, private_key};
+    use std::fs::File;
+    use std::io::BufReader;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+    use tokio_rustls::rustls::sign::CertifiedKey;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    async fn start(drain: Duration) -> ProxyHandle {
+        ProxyServer::new()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .timeouts(Timeouts {
+                drain,
+                ..Timeouts::default()
+            })
+            .start()
+            .await
+            .unwrap()
+    }
+
+    /// Open a SOCKS5 session through `proxy` to a listener that never
+    /// answers, returning both ends so they stay open
+    async fn open_session(proxy: &ProxyHandle) -> (TcpStream, TcpListener) {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = remote.local_addr().unwrap() else {
+            unreachable!()
+        };
+
+        let mut client = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut [0u8; 2]).await.unwrap();
+
+        let mut request = vec![5, 1, 0, 1];
+        request.extend(addr.ip().octets());
+        request.extend(addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        (client, remote)
+    }
+
+    #[tokio::test]
+    async fn sessions_left_at_the_drain_deadline_end_cleanly() {
+        let proxy = start(Duration::ZERO).await;
+        let server = proxy.server().clone();
+        let (mut client, _remote) = open_session(&proxy).await;
+
+        proxy.shutdown();
+        let summary = proxy.await.unwrap();
+
+        assert_eq!(summary.closed, 1);
+        assert_eq!(summary.closed_sessions.len(), 1);
+        // the session left the registry on its own rather than being dropped
+        assert!(server.active_sessions().is_empty());
+        // and the client was told, not just cut off
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_handle_keeps_serving() {
+        let proxy = start(Duration::ZERO).await;
+        let addr = proxy.local_addr();
+        let server = proxy.server().clone();
+        drop(proxy);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn bytes_are_counted_while_relaying() {
+        let metrics = Metrics::new();
+        let proxy = ProxyServer::new()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .metrics(metrics.clone())
+            .start()
+            .await
+            .unwrap();
+        let (mut client, remote) = open_session(&proxy).await;
+        let (mut remote, _) = remote.accept().await.unwrap();
+
+        client.write_all(b"hello").await.unwrap();
+        remote.read_exact(&mut [0u8; 5]).await.unwrap();
+
+        let encoded = String::from_utf8(metrics.encode()).unwrap();
+        let sent = format!(
+            "socks_bytes_total{{direction=\"sent\",listener=\"{}\",user=\"-\"}} 5",
+            proxy.local_addr()
+        );
+        assert!(encoded.contains(&sent), "{}", encoded);
+    }
+
+    #[tokio::test]
+    async fn udp_associations_are_sessions() {
+        let proxy = start(Duration::ZERO).await;
+        let server = proxy.server().clone();
+
+        let mut control = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        control.read_exact(&mut [0u8; 2]).await.unwrap();
+        control
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        // registered right after the reply
+        let mut sessions = server.active_sessions();
+        for _ in 0..100 {
+            if !sessions.is_empty() {
+                break;
+            }
+            tokio::task::yield_now().await;
+            sessions = server.active_sessions();
+        }
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(
+            sessions[0].server2remote.port(),
+            u16::from_be_bytes([reply[8], reply[9]])
+        );
+
+        assert_eq!(server.terminate(|_| true), 1);
+        assert_eq!(control.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert!(server.active_sessions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_are_closed_before_the_handshake() {
+        let metrics = Metrics::new();
+        let mut limits = ConnLimits::default();
+        limits.set("global=1".parse().unwrap());
+        let proxy = ProxyServer::new()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .conn_limits(limits)
+            .metrics(metrics.clone())
+            .start()
+            .await
+            .unwrap();
+        let (_client, _remote) = open_session(&proxy).await;
+
+        // closed without waiting for, or answering, a greeting
+        let mut over = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        let closed = timeout(Duration::from_secs(5), over.read(&mut [0u8; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0) | Err(_))));
+
+        let encoded = String::from_utf8(metrics.encode()).unwrap();
+        let rejected = format!(
+            "socks_connections_rejected_total{{listener=\"{}\",scope=\"global\"}} 1",
+            proxy.local_addr()
+        );
+        assert!(encoded.contains(&rejected), "{}", encoded);
+    }
+
+    /// Has no certificate, which the plaintext clients never ask for
+    #[derive(Debug)]
+    struct NoCertificate;
+
+    impl ResolvesServerCert for NoCertificate {
+        fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+
+    /// The reply to a SOCKS5 greeting sent without TLS to a TLS listener
+    async fn plaintext_greeting(plaintext: bool) -> Vec<u8> {
+        let tls = TlsConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCertificate));
+        let proxy = ProxyServer::new()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .tls(Arc::new(tls))
+            .plaintext(plaintext)
+            .start()
+            .await
+            .unwrap();
+
+        let mut client = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        // closing with the greeting unread resets the connection
+        let len = client.read(&mut reply).await.unwrap_or(0);
+        reply[..len].to_vec()
+    }
+
+    #[tokio::test]
+    async fn tls_listeners_can_serve_plaintext_clients() {
+        assert_eq!(plaintext_greeting(true).await, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn tls_listeners_close_plaintext_clients_by_default() {
+        assert!(plaintext_greeting(false).await.is_empty());
+    }
+
+    fn testdata(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// The user recorded for a SOCKS5 session opened over TLS by a client
+    /// presenting the test client certificate
+    async fn certified_user(field: CertIdentity) -> Option<String> {
+        let files =
+            CertificateFiles::load(testdata("server.pem"), testdata("server-key.pem")).unwrap();
+        let verifier = client_verifier(testdata("ca.pem"), false).unwrap();
+        let serving = Serving {
+            protocols: Protocols {
+                socks5: true,
+                ..Protocols::default()
+            },
+            tls: Some(TlsAcceptor::from(files.server_config(Some(verifier)))),
+            plaintext: false,
+            client_identity: field,
+        };
+
+        let peer = Peer {
+            addr: "127.0.0.1:40000".parse().unwrap(),
+            local: "127.0.0.1:1080".parse().unwrap(),
+        };
+        let server = Arc::new(Server::new(ServerConfig::default(), peer.local));
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let acceptor = serving.tls.clone().unwrap();
+        let permit = server.admit(peer.addr.ip()).unwrap();
+        tokio::spawn(serve_tls(
+            theirs,
+            peer,
+            acceptor,
+            server.clone(),
+            permit,
+            serving,
+        ));
+
+        let mut roots = RootCertStore::empty();
+        for cert in certs(&mut BufReader::new(File::open(testdata("ca.pem")).unwrap())) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let chain = certs(&mut BufReader::new(
+            File::open(testdata("client.pem")).unwrap(),
+        ))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        let key = private_key(&mut BufReader::new(
+            File::open(testdata("client-key.pem")).unwrap(),
+        ))
+        .unwrap()
+        .unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(chain, key)
+            .unwrap();
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = TlsConnector::from(Arc::new(config))
+            .connect(name, ours)
+            .await
+            .unwrap();
+
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = remote.local_addr().unwrap() else {
+            unreachable!()
+        };
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut [0u8; 2]).await.unwrap();
+        let mut request = vec![5, 1, 0, 1];
+        request.extend(addr.ip().octets());
+        request.extend(addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        // registered right after the reply
+        let mut sessions = server.active_sessions();
+        while sessions.is_empty() {
+            tokio::task::yield_now().await;
+            sessions = server.active_sessions();
+        }
+        sessions[0]
+            .user
+            .as_ref()
+            .map(|identity| identity.user.clone())
+    }
+
+    #[tokio::test]
+    async fn tls_sessions_carry_the_certificate_user() {
+        assert_eq!(
+            certified_user(CertIdentity::Cn).await.as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            certified_user(CertIdentity::San).await.as_deref(),
+            Some("alice.test")
+        );
+    }
+}
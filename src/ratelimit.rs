@@ -0,0 +1,475 @@
+use crate::error::MyError;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// Parse a rate in bytes per second, with an optional `k`, `m` or `g` suffix
+/// for powers of 1024, e.g. `512k` or `10M`
+pub fn parse_rate(s: &str) -> Result<u64, MyError> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_lowercase()),
+        _ => (s, 'b'),
+    };
+
+    let multiplier: u64 = match unit {
+        'b' => 1,
+        'k' => 1 << 10,
+        'm' => 1 << 20,
+        'g' => 1 << 30,
+        _ => return Err(MyError::Parse),
+    };
+
+    let rate = digits
+        .parse::<u64>()?
+        .checked_mul(multiplier)
+        .ok_or(MyError::Parse)?;
+
+    if rate == 0 {
+        return Err(MyError::Parse);
+    }
+
+    Ok(rate)
+}
+
+fn parse_side(s: &str) -> Result<Option<u64>, MyError> {
+    match s.trim() {
+        "-" => Ok(None),
+        rate => parse_rate(rate).map(Some),
+    }
+}
+
+/// Upload and download limits in bytes per second, `None` for unlimited.
+/// Written `UP/DOWN`, such as `1M/10M` or `-/10M`, or as a single rate
+/// applying to both.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Limit {
+    /// From the client
+    pub up: Option<u64>,
+    /// To the client
+    pub down: Option<u64>,
+}
+
+impl FromStr for Limit {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((up, down)) => Ok(Limit {
+                up: parse_side(up)?,
+                down: parse_side(down)?,
+            }),
+            None => {
+                let rate = parse_side(s)?;
+                Ok(Limit {
+                    up: rate,
+                    down: rate,
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |rate: Option<u64>| rate.map_or_else(|| "-".to_owned(), |r| r.to_string());
+        write!(f, "{}/{}", side(self.up), side(self.down))
+    }
+}
+
+/// What a limit is shared by, for both rate and connection limits
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Scope {
+    /// Every session of the process
+    Global,
+    /// Every session of one listener
+    Listener,
+    /// Every session of one authenticated user
+    User,
+    /// Every session from one client IP
+    Ip,
+}
+
+impl FromStr for Scope {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(Scope::Global),
+            "listener" => Ok(Scope::Listener),
+            "user" => Ok(Scope::User),
+            "ip" => Ok(Scope::Ip),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Global => "global",
+            Scope::Listener => "listener",
+            Scope::User => "user",
+            Scope::Ip => "ip",
+        })
+    }
+}
+
+/// A limit for one scope, written `scope=UP/DOWN` such as `user=1M/10M`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ScopedLimit {
+    pub scope: Scope,
+    pub limit: Limit,
+}
+
+impl FromStr for ScopedLimit {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scope, limit) = s.split_once('=').ok_or(MyError::Parse)?;
+
+        Ok(ScopedLimit {
+            scope: scope.trim().parse()?,
+            limit: limit.parse()?,
+        })
+    }
+}
+
+/// The limit of every scope. A session is held to all that apply to it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub global: Limit,
+    pub listener: Limit,
+    pub user: Limit,
+    pub ip: Limit,
+}
+
+impl RateLimits {
+    pub fn set(&mut self, scoped: ScopedLimit) {
+        let limit = match scoped.scope {
+            Scope::Global => &mut self.global,
+            Scope::Listener => &mut self.listener,
+            Scope::User => &mut self.user,
+            Scope::Ip => &mut self.ip,
+        };
+        *limit = scoped.limit;
+    }
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Refills at `rate` bytes per second up to one second's worth
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` tokens and return how long to wait before using them. The
+    /// balance may go negative, so a read larger than the bucket is delayed
+    /// instead of never fitting.
+    fn reserve(&self, n: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        let refill = now.duration_since(state.last).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.rate) - n as f64;
+        state.last = now;
+
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// The buckets one direction of a session draws from
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    /// Wait until `n` bytes may pass every bucket
+    pub async fn take(&self, n: u64) {
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.reserve(n))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Buckets shared by every session with the same key. Entries go away once
+/// no session holds them.
+#[derive(Debug)]
+struct Keyed<K> {
+    buckets: Mutex<HashMap<K, Weak<TokenBucket>>>,
+}
+
+impl<K: Hash + Eq> Keyed<K> {
+    fn new() -> Self {
+        Keyed {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: K, rate: u64) -> Arc<TokenBucket> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if let Some(bucket) = buckets.get(&key).and_then(Weak::upgrade) {
+            return bucket;
+        }
+
+        buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+        let bucket = Arc::new(TokenBucket::new(rate));
+        buckets.insert(key, Arc::downgrade(&bucket));
+        bucket
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    limits: RateLimits,
+    global_up: Option<Arc<TokenBucket>>,
+    global_down: Option<Arc<TokenBucket>>,
+    users_up: Keyed<String>,
+    users_down: Keyed<String>,
+    ips_up: Keyed<IpAddr>,
+    ips_down: Keyed<IpAddr>,
+}
+
+/// Hands out the throttles of each session. Clones share the global, user
+/// and IP buckets, listener buckets belong to each listener.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    shared: Arc<Shared>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimits::default())
+    }
+}
+
+fn bucket(rate: Option<u64>) -> Option<Arc<TokenBucket>> {
+    rate.map(|rate| Arc::new(TokenBucket::new(rate)))
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            shared: Arc::new(Shared {
+                limits,
+                global_up: bucket(limits.global.up),
+                global_down: bucket(limits.global.down),
+                users_up: Keyed::new(),
+                users_down: Keyed::new(),
+                ips_up: Keyed::new(),
+                ips_down: Keyed::new(),
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.shared.limits
+    }
+
+    /// Buckets for a new listener
+    pub fn listener(&self) -> ListenerLimiter {
+        ListenerLimiter {
+            limiter: self.clone(),
+            up: bucket(self.shared.limits.listener.up),
+            down: bucket(self.shared.limits.listener.down),
+        }
+    }
+}
+
+/// The rate limiter of one listener
+#[derive(Debug, Clone)]
+pub struct ListenerLimiter {
+    limiter: RateLimiter,
+    up: Option<Arc<TokenBucket>>,
+    down: Option<Arc<TokenBucket>>,
+}
+
+impl ListenerLimiter {
+    /// The upload and download throttles of a session from `client` as `user`
+    pub fn session(&self, user: Option<&str>, client: IpAddr) -> (Throttle, Throttle) {
+        let shared = &self.limiter.shared;
+        let limits = &shared.limits;
+
+        let mut up = Throttle::default();
+        let mut down = Throttle::default();
+
+        up.buckets.extend(shared.global_up.clone());
+        down.buckets.extend(shared.global_down.clone());
+        up.buckets.extend(self.up.clone());
+        down.buckets.extend(self.down.clone());
+
+        if let Some(user) = user {
+            if let Some(rate) = limits.user.up {
+                up.buckets.push(shared.users_up.get(user.to_owned(), rate));
+            }
+            if let Some(rate) = limits.user.down {
+                down.buckets
+                    .push(shared.users_down.get(user.to_owned(), rate));
+            }
+        }
+
+        if let Some(rate) = limits.ip.up {
+            up.buckets.push(shared.ips_up.get(client, rate));
+        }
+        if let Some(rate) = limits.ip.down {
+            down.buckets.push(shared.ips_down.get(client, rate));
+        }
+
+        (up, down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{advance, pause};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn limited(limit: &str) -> ListenerLimiter {
+        let mut limits = RateLimits::default();
+        limits.set(limit.parse().unwrap());
+        RateLimiter::new(limits).listener()
+    }
+
+    fn local() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[test]
+    fn rates_take_units() {
+        assert_eq!(parse_rate("512").unwrap(), 512);
+        assert_eq!(parse_rate("512k").unwrap(), 512 << 10);
+        assert_eq!(parse_rate("10M").unwrap(), 10 << 20);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("0k").is_err());
+        assert!(parse_rate("10x").is_err());
+    }
+
+    #[test]
+    fn limits_can_leave_a_side_unlimited() {
+        let limit: Limit = "-/10M".parse().unwrap();
+        assert_eq!(limit.up, None);
+        assert_eq!(limit.down, Some(10 << 20));
+        assert_eq!(limit.to_string(), "-/10485760");
+    }
+
+    #[tokio::test]
+    async fn a_full_bucket_lets_a_burst_through() {
+        pause();
+        let bucket = TokenBucket::new(1000);
+
+        assert_eq!(bucket.reserve(600), Duration::ZERO);
+        assert_eq!(bucket.reserve(400), Duration::ZERO);
+        assert_eq!(bucket.reserve(100), ms(100));
+    }
+
+    #[tokio::test]
+    async fn an_empty_bucket_waits_for_the_refill() {
+        pause();
+        let bucket = TokenBucket::new(1000);
+        bucket.reserve(1000);
+
+        assert_eq!(bucket.reserve(500), ms(500));
+        advance(ms(500)).await;
+        assert_eq!(bucket.reserve(250), ms(250));
+
+        // larger than the bucket, the read still passes once paid off
+        advance(ms(250)).await;
+        assert_eq!(bucket.reserve(3000), Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn buckets_hold_at_most_a_second() {
+        pause();
+        let bucket = TokenBucket::new(1000);
+
+        advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn throttles_wait_for_their_slowest_bucket() {
+        pause();
+        let mut limits = RateLimits::default();
+        limits.set("global=1000".parse().unwrap());
+        limits.set("ip=500".parse().unwrap());
+        let (up, _) = RateLimiter::new(limits).listener().session(None, local());
+
+        let started = Instant::now();
+        up.take(1500).await;
+        assert!(started.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn unlimited_sides_have_no_buckets() {
+        let (up, down) = limited("user=-/1000").session(Some("alice"), local());
+        assert!(up.buckets.is_empty());
+        assert_eq!(down.buckets.len(), 1);
+
+        let (up, down) = RateLimiter::default().listener().session(None, local());
+        assert!(up.buckets.is_empty() && down.buckets.is_empty());
+
+        // nothing to wait for, even with the clock stopped
+        pause();
+        let started = Instant::now();
+        up.take(u64::MAX).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn sessions_of_one_user_share_a_bucket() {
+        let limiter = limited("user=1000");
+
+        let (first, _) = limiter.session(Some("alice"), local());
+        let (second, _) = limiter.session(Some("alice"), local());
+        let (other, _) = limiter.session(Some("bob"), local());
+
+        assert!(Arc::ptr_eq(&first.buckets[0], &second.buckets[0]));
+        assert!(!Arc::ptr_eq(&first.buckets[0], &other.buckets[0]));
+
+        // a new bucket once no session holds the old one
+        let old = Arc::downgrade(&first.buckets[0]);
+        drop((first, second));
+        assert!(old.upgrade().is_none());
+    }
+}
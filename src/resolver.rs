@@ -0,0 +1,401 @@
+use crate::error::MyError;
+use async_trait::async_trait;
+use clap::ArgEnum;
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tracing::debug;
+
+const DNS_PORT: u16 = 53;
+
+/// Turns host names into addresses.
+#[async_trait]
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// Addresses of `host` in the order they should be tried. Names that
+    /// don't exist fail with `HostUnreachable` so they get a matching reply.
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// The operating system's resolver, with whatever caching it does itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct System;
+
+#[async_trait]
+impl Resolver for System {
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs: Vec<IpAddr> = lookup_host((host, 0)).await?.map(|a| a.ip()).collect();
+
+        if addrs.is_empty() {
+            return Err(ErrorKind::HostUnreachable.into());
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Which address families a lookup returns. The `prefer` variants only fall
+/// back to the other family when a name has no address in the preferred one.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ArgEnum)]
+pub enum Family {
+    /// Both, IPv4 and IPv6 queried together
+    Any,
+    Ipv4,
+    Ipv6,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl Family {
+    /// Keep the addresses this family allows, for entries that don't come
+    /// from DNS
+    fn pick(self, addrs: &[IpAddr]) -> Vec<IpAddr> {
+        let v4 = || addrs.iter().copied().filter(IpAddr::is_ipv4);
+        let v6 = || addrs.iter().copied().filter(IpAddr::is_ipv6);
+
+        match self {
+            Family::Any => addrs.to_vec(),
+            Family::Ipv4 => v4().collect(),
+            Family::Ipv6 => v6().collect(),
+            Family::PreferIpv4 if v4().next().is_some() => v4().collect(),
+            Family::PreferIpv6 if v6().next().is_some() => v6().collect(),
+            Family::PreferIpv4 | Family::PreferIpv6 => addrs.to_vec(),
+        }
+    }
+}
+
+impl FromStr for Family {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "any" => Ok(Family::Any),
+            "ipv4" => Ok(Family::Ipv4),
+            "ipv6" => Ok(Family::Ipv6),
+            "prefer-ipv4" => Ok(Family::PreferIpv4),
+            "prefer-ipv6" => Ok(Family::PreferIpv6),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
+impl From<Family> for LookupIpStrategy {
+    fn from(family: Family) -> Self {
+        match family {
+            Family::Any => LookupIpStrategy::Ipv4AndIpv6,
+            Family::Ipv4 => LookupIpStrategy::Ipv4Only,
+            Family::Ipv6 => LookupIpStrategy::Ipv6Only,
+            Family::PreferIpv4 => LookupIpStrategy::Ipv4thenIpv6,
+            Family::PreferIpv6 => LookupIpStrategy::Ipv6thenIpv4,
+        }
+    }
+}
+
+/// A DNS server, written `udp://1.1.1.1`, `tcp://[2606:4700::1111]:53` or
+/// just the address for UDP on port 53
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct NameServer {
+    pub addr: SocketAddr,
+    pub tcp: bool,
+}
+
+impl FromStr for NameServer {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tcp, addr) = match s.split_once("://") {
+            Some((scheme, addr)) => match scheme.to_ascii_lowercase().as_str() {
+                "udp" => (false, addr),
+                "tcp" => (true, addr),
+                _ => return Err(MyError::Parse),
+            },
+            None => (false, s),
+        };
+
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => {
+                let ip = addr.trim_start_matches('[').trim_end_matches(']');
+                SocketAddr::new(ip.parse().map_err(|_| MyError::Parse)?, DNS_PORT)
+            }
+        };
+
+        Ok(NameServer { addr, tcp })
+    }
+}
+
+impl fmt::Display for NameServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tcp { "tcp" } else { "udp" };
+        write!(f, "{}://{}", scheme, self.addr)
+    }
+}
+
+/// A static hosts entry, written `name=addr[,addr...]`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct HostEntry {
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl FromStr for HostEntry {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addrs) = s.split_once('=').ok_or(MyError::Parse)?;
+        let name = normalize(name.trim());
+
+        if name.is_empty() {
+            return Err(MyError::Parse);
+        }
+
+        let addrs = addrs
+            .split(',')
+            .map(|a| a.trim().parse().map_err(|_| MyError::Parse))
+            .collect::<Result<Vec<IpAddr>, MyError>>()?;
+
+        Ok(HostEntry { name, addrs })
+    }
+}
+
+/// Settings for `Dns`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DnsConfig {
+    /// Servers to query, those of `/etc/resolv.conf` when empty
+    pub servers: Vec<NameServer>,
+    /// Answered without a query, ahead of the system hosts file
+    pub hosts: Vec<HostEntry>,
+    pub family: Family,
+    /// Most answers kept. Answers are kept for their TTL.
+    pub cache_size: usize,
+    /// How long a name that doesn't exist is remembered
+    pub negative_ttl: Duration,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            servers: Vec::new(),
+            hosts: Vec::new(),
+            family: Family::Any,
+            cache_size: 1024,
+            negative_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Queries DNS servers directly, caching answers for their TTL and failed
+/// lookups for the negative TTL
+#[derive(Debug, Clone)]
+pub struct Dns {
+    resolver: TokioAsyncResolver,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    family: Family,
+}
+
+impl Dns {
+    pub fn new(config: &DnsConfig) -> Result<Self, MyError> {
+        let (resolver_config, mut opts) = if config.servers.is_empty() {
+            read_system_conf().map_err(|_| MyError::IO)?
+        } else {
+            let mut resolver_config = ResolverConfig::new();
+            for server in &config.servers {
+                let protocol = if server.tcp {
+                    Protocol::Tcp
+                } else {
+                    Protocol::Udp
+                };
+                resolver_config.add_name_server(NameServerConfig::new(server.addr, protocol));
+            }
+            (resolver_config, ResolverOpts::default())
+        };
+
+        opts.ip_strategy = config.family.into();
+        opts.cache_size = config.cache_size;
+        opts.negative_min_ttl = Some(config.negative_ttl);
+        opts.negative_max_ttl = Some(config.negative_ttl);
+
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|entry| (entry.name.clone(), entry.addrs.clone()))
+            .collect();
+
+        Ok(Dns {
+            resolver: TokioAsyncResolver::tokio(resolver_config, opts),
+            hosts,
+            family: config.family,
+        })
+    }
+}
+
+fn lookup_error(e: ResolveError) -> io::Error {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => {
+            io::Error::new(ErrorKind::HostUnreachable, e.to_string())
+        }
+        ResolveErrorKind::Timeout => io::Error::new(ErrorKind::TimedOut, e.to_string()),
+        _ => io::Error::other(e.to_string()),
+    }
+}
+
+#[async_trait]
+impl Resolver for Dns {
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Some(addrs) = self.hosts.get(&normalize(host)) {
+            let addrs = self.family.pick(addrs);
+            debug!(host, ?addrs, "resolved from hosts table");
+
+            if addrs.is_empty() {
+                return Err(ErrorKind::HostUnreachable.into());
+            }
+            return Ok(addrs);
+        }
+
+        match self.resolver.lookup_ip(host).await {
+            Ok(lookup) => {
+                let addrs: Vec<IpAddr> = lookup.iter().collect();
+                debug!(host, ?addrs, "resolved");
+                Ok(addrs)
+            }
+            Err(e) => {
+                debug!(host, error = %e, "lookup failed");
+                Err(lookup_error(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::net::UdpSocket;
+
+    const V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+    /// A DNS server answering every A query with `V4` and every AAAA query
+    /// with `V6`
+    async fn fake_dns() -> NameServer {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                // the question ends after its name, type and class
+                let mut end = 12;
+                while buf[end] != 0 {
+                    end += buf[end] as usize + 1;
+                }
+                end += 5;
+                let qtype = u16::from_be_bytes([buf[end - 4], buf[end - 3]]);
+
+                let rdata = match qtype {
+                    1 => V4.octets().to_vec(),
+                    28 => V6.octets().to_vec(),
+                    _ => Vec::new(),
+                };
+                let answers = u8::from(!rdata.is_empty());
+
+                let mut reply = buf[..2].to_vec();
+                reply.extend([0x81, 0x80, 0, 1, 0, answers, 0, 0, 0, 0]);
+                reply.extend(&buf[12..end.min(n)]);
+                if answers == 1 {
+                    // a pointer to the question's name, the type, class IN
+                    // and a TTL of 60
+                    reply.extend([0xC0, 12]);
+                    reply.extend(qtype.to_be_bytes());
+                    reply.extend([0, 1, 0, 0, 0, 60, 0, rdata.len() as u8]);
+                    reply.extend(rdata);
+                }
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+
+        NameServer { addr, tcp: false }
+    }
+
+    async fn dns(family: Family, hosts: &[&str]) -> Dns {
+        Dns::new(&DnsConfig {
+            servers: vec![fake_dns().await],
+            hosts: hosts.iter().map(|h| h.parse().unwrap()).collect(),
+            family,
+            ..DnsConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn hosts_entries_win_over_dns() {
+        let dns = dns(Family::Any, &["App.test.=10.0.0.1"]).await;
+
+        let found = dns.lookup("app.TEST").await.unwrap();
+        assert_eq!(found, addrs(&["10.0.0.1"]));
+        let found = dns.lookup("app.test.").await.unwrap();
+        assert_eq!(found, addrs(&["10.0.0.1"]));
+
+        // other names are still asked for
+        let mut found = dns.lookup("other.test").await.unwrap();
+        found.sort();
+        assert_eq!(found, vec![IpAddr::V4(V4), IpAddr::V6(V6)]);
+    }
+
+    #[tokio::test]
+    async fn family_filters_dns_answers() {
+        let found = dns(Family::Ipv4, &[]).await.lookup("a.test").await;
+        assert_eq!(found.unwrap(), vec![IpAddr::V4(V4)]);
+
+        let found = dns(Family::Ipv6, &[]).await.lookup("a.test").await;
+        assert_eq!(found.unwrap(), vec![IpAddr::V6(V6)]);
+
+        let found = dns(Family::PreferIpv6, &[]).await.lookup("a.test").await;
+        assert_eq!(found.unwrap(), vec![IpAddr::V6(V6)]);
+    }
+
+    #[tokio::test]
+    async fn family_filters_hosts_entries() {
+        let hosts = ["dual.test=10.0.0.1,fd00::1,10.0.0.2", "v4.test=10.0.0.1"];
+
+        let resolver = dns(Family::Ipv4, &hosts).await;
+        let found = resolver.lookup("dual.test").await.unwrap();
+        assert_eq!(found, addrs(&["10.0.0.1", "10.0.0.2"]));
+
+        let resolver = dns(Family::Ipv6, &hosts).await;
+        assert_eq!(
+            resolver.lookup("dual.test").await.unwrap(),
+            addrs(&["fd00::1"])
+        );
+        let missing = resolver.lookup("v4.test").await.unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::HostUnreachable);
+
+        // preferring a family falls back to the other
+        let resolver = dns(Family::PreferIpv6, &hosts).await;
+        assert_eq!(
+            resolver.lookup("v4.test").await.unwrap(),
+            addrs(&["10.0.0.1"])
+        );
+        assert_eq!(
+            resolver.lookup("dual.test").await.unwrap(),
+            addrs(&["fd00::1"])
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::acl::{Conditions, Request};
+use crate::connector::{Chain, Connector, Direct, Upstream};
+use crate::error::MyError;
+use crate::socks::Destination;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+/// Where a routed connection goes
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Outbound {
+    Direct,
+    /// Through the named upstream group
+    Group(String),
+    Reject,
+}
+
+impl FromStr for Outbound {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(MyError::Parse),
+            "direct" => Ok(Outbound::Direct),
+            "reject" => Ok(Outbound::Reject),
+            group => Ok(Outbound::Group(group.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outbound::Direct => f.write_str("direct"),
+            Outbound::Group(name) => write!(f, "via {}", name),
+            Outbound::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+/// How a routing decision shows in logs and metrics: the group name,
+/// `direct` or `reject`, or `default` when no route matched
+pub fn route_label(route: Option<&Outbound>) -> &str {
+    match route {
+        Some(Outbound::Direct) => "direct",
+        Some(Outbound::Group(name)) => name,
+        Some(Outbound::Reject) => "reject",
+        None => "default",
+    }
+}
+
+/// A routing rule, written as the outbound followed by the same conditions
+/// as an access rule, e.g. `corp domain=.corp.example.com,.internal` or
+/// `reject port=25`. `direct` and `reject` are reserved, anything else names
+/// an upstream group.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Route {
+    pub outbound: Outbound,
+    pub conditions: Conditions,
+}
+
+impl FromStr for Route {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let outbound = words.next().ok_or(MyError::Parse)?.parse()?;
+
+        Ok(Route {
+            outbound,
+            conditions: Conditions::parse(words)?,
+        })
+    }
+}
+
+/// A named chain of upstream proxies, written `name=url[,url...]`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UpstreamGroup {
+    pub name: String,
+    pub hops: Vec<Upstream>,
+}
+
+impl FromStr for UpstreamGroup {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, hops) = s.split_once('=').ok_or(MyError::Parse)?;
+
+        if matches!(name.parse()?, Outbound::Direct | Outbound::Reject) {
+            return Err(MyError::Parse);
+        }
+
+        Ok(UpstreamGroup {
+            name: name.to_owned(),
+            hops: hops
+                .split(',')
+                .map(|hop| hop.trim().parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Picks an outbound per request. The first matching route decides; when
+/// none does the server's default connector is used.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    groups: HashMap<String, Arc<dyn Connector>>,
+    direct: Direct,
+}
+
+impl Router {
+    /// Direct routes and the first hop of each group connect through
+    /// `direct`. Fails if a route names a group that doesn't exist
+    pub fn new(
+        routes: Vec<Route>,
+        groups: Vec<UpstreamGroup>,
+        direct: Direct,
+    ) -> Result<Self, MyError> {
+        let groups: HashMap<String, Arc<dyn Connector>> = groups
+            .into_iter()
+            .map(|g| {
+                let chain = Chain::new(g.hops).via(direct.clone());
+                (g.name, Arc::new(chain) as Arc<dyn Connector>)
+            })
+            .collect();
+
+        let mut router = Router {
+            routes: Vec::new(),
+            groups,
+            direct,
+        };
+
+        for route in routes {
+            router.add_route(route)?;
+        }
+
+        Ok(router)
+    }
+
+    pub fn add_route(&mut self, route: Route) -> Result<(), MyError> {
+        if let Outbound::Group(name) = &route.outbound {
+            if !self.groups.contains_key(name) {
+                return Err(MyError::Parse);
+            }
+        }
+
+        self.routes.push(route);
+        Ok(())
+    }
+
+    /// Register an outbound group with a custom connector
+    pub fn add_group(&mut self, name: impl Into<String>, connector: Arc<dyn Connector>) {
+        self.groups.insert(name.into(), connector);
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn decide(&self, req: &Request) -> Option<&Outbound> {
+        self.routes
+            .iter()
+            .find(|route| route.conditions.matches(req))
+            .map(|route| &route.outbound)
+    }
+
+    /// Connect through `outbound`, see `Connector::connect_resolved` for
+    /// `addrs`. Rejections fail with `PermissionDenied` so they are reported
+    /// like an access rule denial.
+    pub async fn connect(
+        &self,
+        outbound: &Outbound,
+        dest: &Destination,
+        addrs: &[IpAddr],
+    ) -> io::Result<TcpStream> {
+        match outbound {
+            Outbound::Direct => self.direct.connect_resolved(dest, addrs).await,
+            Outbound::Group(name) => match self.groups.get(name) {
+                Some(group) => group.connect_resolved(dest, addrs).await,
+                None => Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    "unknown upstream group",
+                )),
+            },
+            Outbound::Reject => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "rejected by route",
+            )),
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use std::net::IpAddr;
+use crate::error::MyError;
+use bytes::{BufMut, BytesMut};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Address {
@@ -28,28 +32,113 @@ impl Destination {
             None
         }
     }
-}
 
-impl From<&Destination> for String {
-    fn from(dest: &Destination) -> Self {
-        match &dest.addr {
+    /// Append ATYP, DST.ADDR and DST.PORT as used in SOCKS5 requests, replies
+    /// and UDP headers. Names longer than 255 bytes are rejected.
+    pub fn put_socks5(&self, buf: &mut BytesMut) -> Result<(), MyError> {
+        match &self.addr {
+            Address::IP(IpAddr::V4(ip)) => {
+                buf.put_u8(1);
+                buf.extend(ip.octets());
+            }
             Address::Name(name) => {
-                format!("{}:{}", name, dest.port)
+                let len = u8::try_from(name.len()).map_err(|_| MyError::Parse)?;
+                buf.put_u8(3);
+                buf.put_u8(len);
+                buf.extend(name.as_bytes());
             }
-            Address::IP(ip) => {
-                format!("{}:{}", ip, dest.port)
+            Address::IP(IpAddr::V6(ip)) => {
+                buf.put_u8(4);
+                buf.extend(ip.octets());
             }
         }
+
+        buf.put_u16(self.port);
+        Ok(())
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(addr: SocketAddr) -> Self {
+        Destination {
+            addr: Address::IP(addr.ip()),
+            port: addr.port(),
+        }
+    }
+}
+
+/// `host:port`, with IPv6 addresses in brackets as in `[::1]:443`
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.addr {
+            Address::Name(name) => write!(f, "{}:{}", name, self.port),
+            Address::IP(IpAddr::V4(ip)) => write!(f, "{}:{}", ip, self.port),
+            Address::IP(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s.rsplit_once(':').ok_or(MyError::Parse)?;
+        let port = port.parse()?;
+
+        let addr = if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Address::IP(IpAddr::V6(v6.parse().map_err(|_| MyError::Parse)?))
+        } else if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            Address::IP(IpAddr::V4(ip))
+        } else if !host.is_empty() && !host.contains(':') {
+            Address::Name(host.to_owned())
+        } else {
+            // IPv6 addresses need brackets to tell them from the port
+            return Err(MyError::Parse);
+        };
+
+        Ok(Destination { addr, port })
     }
 }
 
 #[derive(Debug)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum SOCKS {
     V4 = 4,
     V5 = 5,
 }
 
+/// A request command independent of the protocol version it arrived in
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Cmd {
+    Connect,
+    Bind,
+    Udp,
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cmd::Connect => "connect",
+            Cmd::Bind => "bind",
+            Cmd::Udp => "udp",
+        })
+    }
+}
+
+impl FromStr for Cmd {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "connect" => Ok(Cmd::Connect),
+            "bind" => Ok(Cmd::Bind),
+            "udp" => Ok(Cmd::Udp),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(u8)]
 pub enum SOCKS4Cmd {
@@ -57,9 +146,19 @@ pub enum SOCKS4Cmd {
     Bind = 2,
 }
 
+impl From<&SOCKS4Cmd> for Cmd {
+    fn from(cmd: &SOCKS4Cmd) -> Self {
+        match cmd {
+            SOCKS4Cmd::Connect => Cmd::Connect,
+            SOCKS4Cmd::Bind => Cmd::Bind,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SOCKS4Init {
     pub cmd: SOCKS4Cmd,
+    #[allow(dead_code)]
     pub ident: Vec<u8>,
     pub dest: Destination,
 }
@@ -88,7 +187,7 @@ pub struct SOCKS5AuthRequest {
     pub pw: Vec<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum SOCKS5AuthReply {
     Accepted = 0,
@@ -104,22 +203,38 @@ pub enum SOCKS5Cmd {
     Udp = 3,
 }
 
+impl From<&SOCKS5Cmd> for Cmd {
+    fn from(cmd: &SOCKS5Cmd) -> Self {
+        match cmd {
+            SOCKS5Cmd::Connect => Cmd::Connect,
+            SOCKS5Cmd::Bind => Cmd::Bind,
+            SOCKS5Cmd::Udp => Cmd::Udp,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SOCKS5ConnectRequest {
     pub cmd: SOCKS5Cmd,
     pub dest: Destination,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum SOCKS5ConnectReply {
     Accepted = 0,
     Failure = 1,
-    // NotAllowed = 2,
+    NotAllowed = 2,
     NetworkUnreachable = 3,
     HostUnreachable = 4,
     ConnectionRefused = 5,
-    // TTLExpired = 6,
-    CommandNotSupported = 7,
+    TTLExpired = 6,
+    // CommandNotSupported = 7,
     // AddressTypeNotSupported = 8,
 }
+
+#[derive(Debug)]
+pub struct SOCKS5UdpHeader {
+    pub frag: u8,
+    pub dest: Destination,
+}
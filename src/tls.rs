@@ -0,0 +1,199 @@
+use crate::auth::Identity;
+use crate::error::MyError;
+use crate::watch::reload_on_change;
+use clap::ArgEnum;
+use rustls_pemfile::{certs, private_key};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig as TlsConfig};
+use tracing::{info, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// A certificate chain and its private key read from PEM files. Clones share
+/// the loaded key, so a reload reaches every listener serving it.
+#[derive(Debug, Clone)]
+pub struct CertificateFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    current: Arc<RwLock<Arc<CertifiedKey>>>,
+}
+
+impl CertificateFiles {
+    pub fn load(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self, MyError> {
+        let cert = cert.as_ref().to_owned();
+        let key = key.as_ref().to_owned();
+        let current = read_certified_key(&cert, &key)?;
+
+        Ok(CertificateFiles {
+            cert,
+            key,
+            current: Arc::new(RwLock::new(Arc::new(current))),
+        })
+    }
+
+    /// Re-read both files, keeping the current certificate if they can't be
+    /// loaded or don't match.
+    pub fn reload(&self) -> Result<(), MyError> {
+        let current = read_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(current);
+        Ok(())
+    }
+
+    /// Reload in the background whenever either file changes or the process
+    /// receives SIGHUP. Must be called from within a tokio runtime; the task
+    /// ends once every clone of this `CertificateFiles` is dropped.
+    pub fn watch(self) -> Self {
+        let (cert, key) = (self.cert.clone(), self.key.clone());
+
+        tokio::spawn(reload_on_change(
+            vec![cert.clone(), key.clone()],
+            Arc::downgrade(&self.current),
+            move |current| match read_certified_key(&cert, &key) {
+                Ok(new) => {
+                    info!(cert = %cert.display(), "reloaded TLS certificate");
+                    *current.write().unwrap() = Arc::new(new);
+                }
+                Err(e) => {
+                    warn!(cert = %cert.display(), error = %e, "couldn't reload TLS certificate")
+                }
+            },
+        ));
+        self
+    }
+
+    /// Settings for a TLS listener presenting this certificate, asking
+    /// clients for theirs when there is a `client_verifier`
+    pub fn server_config(
+        &self,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> Arc<TlsConfig> {
+        let builder = TlsConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions");
+
+        let config = match client_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        }
+        .with_cert_resolver(Arc::new(self.clone()));
+
+        Arc::new(config)
+    }
+}
+
+impl ResolvesServerCert for CertificateFiles {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, MyError> {
+    let chain = certs(&mut BufReader::new(File::open(cert)?)).collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(MyError::Parse);
+    }
+
+    let key = private_key(&mut BufReader::new(File::open(key)?))?.ok_or(MyError::Parse)?;
+
+    // also checks that the key belongs to the certificate
+    CertifiedKey::from_der(chain, key, &provider()).map_err(|_| MyError::Parse)
+}
+
+/// Verify client certificates against the CA certificates in the PEM file
+/// `ca`. With `optional` clients may also connect without a certificate,
+/// to authenticate another way.
+pub fn client_verifier(
+    ca: impl AsRef<Path>,
+    optional: bool,
+) -> Result<Arc<dyn ClientCertVerifier>, MyError> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut BufReader::new(File::open(ca)?)) {
+        roots.add(cert?).map_err(|_| MyError::Parse)?;
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+    if optional {
+        builder = builder.allow_unauthenticated();
+    }
+
+    // fails when there are no CA certificates
+    builder.build().map_err(|_| MyError::Parse)
+}
+
+/// The part of a verified client certificate that names the user
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ArgEnum)]
+pub enum CertIdentity {
+    /// The subject's common name
+    #[default]
+    Cn,
+    /// The first DNS name, email address or URI among the subject
+    /// alternative names
+    San,
+}
+
+impl CertIdentity {
+    /// The user `cert` names, if it has the field
+    pub fn of(self, cert: &CertificateDer<'_>) -> Option<Identity> {
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+
+        let name = match self {
+            CertIdentity::Cn => cert
+                .subject()
+                .iter_common_name()
+                .next()?
+                .as_str()
+                .ok()?
+                .to_owned(),
+            CertIdentity::San => cert
+                .subject_alternative_name()
+                .ok()??
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                        Some(s.to_string())
+                    }
+                    _ => None,
+                })?,
+        };
+
+        (!name.is_empty()).then(|| Identity::new(name))
+    }
+}
+
+impl FromStr for CertIdentity {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cn" => Ok(CertIdentity::Cn),
+            "san" => Ok(CertIdentity::San),
+            _ => Err(MyError::Parse),
+        }
+    }
+}
+
+impl fmt::Display for CertIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CertIdentity::Cn => "cn",
+            CertIdentity::San => "san",
+        })
+    }
+}
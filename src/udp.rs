@@ -0,0 +1,373 @@
+use crate::acl::Request;
+use crate::auth::Identity;
+use crate::error::MyError;
+use crate::metrics::ByteCounters;
+use crate::parse::socks5_udp_header;
+use crate::ratelimit::Throttle;
+use crate::server::{Server, Traffic};
+use crate::socks::{Address, Cmd, Destination};
+use bytes::BytesMut;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+
+// largest payload a UDP datagram can carry
+const MAX_DATAGRAM: usize = 65535;
+
+// datagrams to domain names waiting for a lookup, more are dropped
+const MAX_PENDING_LOOKUPS: usize = 64;
+
+#[derive(Debug)]
+pub struct UdpRelay {
+    /// Faces the client, on the address it reached the proxy at
+    socket: UdpSocket,
+    forwarder: Arc<Forwarder>,
+    client_port: u16,
+    client_addr: Option<SocketAddr>,
+}
+
+/// Sends client datagrams on. Shared with the tasks that look up domain
+/// names, so a slow lookup doesn't hold up the rest of the association.
+#[derive(Debug)]
+struct Forwarder {
+    /// Bound to every local address, since a client on loopback may still
+    /// send to remote hosts
+    socket: UdpSocket,
+    /// Where the client has sent datagrams, the only hosts whose replies
+    /// are passed back
+    contacted: Mutex<HashSet<SocketAddr>>,
+    client_ip: IpAddr,
+    server: Arc<Server>,
+    user: Option<Identity>,
+    traffic: Arc<Traffic>,
+    bytes: ByteCounters,
+    /// Rate limits from and to the client
+    up: Throttle,
+    down: Throttle,
+}
+
+impl UdpRelay {
+    /// Bind the relay socket on `local`, and one on the unspecified address of
+    /// the same family to reach remote hosts. `client` is the peer of the controlling
+    /// TCP connection and `requested` is the DST.ADDR/DST.PORT from the UDP
+    /// ASSOCIATE request, which the client may leave as zeros. Every datagram
+    /// the client sends is checked against the access rules of `server` as
+    /// `user`.
+    pub async fn bind(
+        local: IpAddr,
+        client: SocketAddr,
+        requested: &Destination,
+        server: Arc<Server>,
+        user: Option<Identity>,
+    ) -> Result<Self, MyError> {
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        let any = match local {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let outbound = UdpSocket::bind(SocketAddr::new(any, 0)).await?;
+
+        let client_ip = match &requested.addr {
+            Address::IP(ip) if !ip.is_unspecified() => *ip,
+            _ => client.ip(),
+        };
+
+        let bytes = server
+            .metrics()
+            .bytes(user.as_ref().map_or("-", |id| id.user.as_str()));
+        let (up, down) = server.throttles(user.as_ref(), client.ip());
+
+        Ok(UdpRelay {
+            socket,
+            forwarder: Arc::new(Forwarder {
+                socket: outbound,
+                contacted: Mutex::new(HashSet::new()),
+                client_ip,
+                server,
+                user,
+                traffic: Arc::new(Traffic::new()),
+                bytes,
+                up,
+                down,
+            }),
+            client_port: requested.port,
+            client_addr: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, MyError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Payload bytes relayed so far
+    pub fn traffic(&self) -> &Arc<Traffic> {
+        &self.forwarder.traffic
+    }
+
+    fn is_client(&mut self, from: SocketAddr) -> bool {
+        match self.client_addr {
+            Some(addr) => addr == from,
+            None => {
+                if from.ip() != self.forwarder.client_ip
+                    || (self.client_port != 0 && from.port() != self.client_port)
+                {
+                    return false;
+                }
+                // first datagram from the client fixes its address for the
+                // rest of the association
+                self.client_addr = Some(from);
+                true
+            }
+        }
+    }
+
+    /// Relay datagrams in both directions until an unrecoverable socket error.
+    /// The caller is responsible for ending the association when the
+    /// controlling TCP connection closes.
+    /// Pending lookups are abandoned when the returned future is dropped.
+    pub async fn run(&mut self) -> Result<(), MyError> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut remote_buf = vec![0u8; MAX_DATAGRAM];
+        let mut lookups = JoinSet::new();
+
+        loop {
+            let (received, from_client) = tokio::select! {
+                r = self.socket.recv_from(&mut buf) => (r, true),
+                r = self.forwarder.socket.recv_from(&mut remote_buf) => (r, false),
+                Some(_) = lookups.join_next() => continue,
+            };
+
+            let (len, from) = match received {
+                Ok(r) => r,
+                // ICMP errors from earlier sends can surface here, they only
+                // concern a single datagram
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if !from_client {
+                if let Some(client) = self.client_addr {
+                    if self.forwarder.contacted.lock().unwrap().contains(&from) {
+                        self.reply(from, &remote_buf[..len], client).await;
+                    }
+                }
+                continue;
+            }
+
+            if !self.is_client(from) {
+                continue;
+            }
+
+            let (payload, header) = match socks5_udp_header(&buf[..len]) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+
+            // fragmentation is optional and we don't implement it, so drop
+            // anything that is part of a fragment sequence
+            if header.frag != 0 {
+                continue;
+            }
+
+            match &header.dest.addr {
+                Address::IP(_) => self.forwarder.forward(&header.dest, payload, &[]).await,
+                Address::Name(_) if lookups.len() < MAX_PENDING_LOOKUPS => {
+                    let forwarder = self.forwarder.clone();
+                    let payload = payload.to_vec();
+
+                    lookups.spawn(async move {
+                        forwarder.forward_resolved(&header.dest, &payload).await
+                    });
+                }
+                Address::Name(_) => {}
+            }
+        }
+    }
+
+    /// Send a datagram from `from` back to the client
+    async fn reply(&self, from: SocketAddr, payload: &[u8], client: SocketAddr) {
+        let forwarder = &self.forwarder;
+        let mut msg = udp_header(from);
+        msg.extend_from_slice(payload);
+
+        forwarder.down.take(payload.len() as u64).await;
+
+        if self.socket.send_to(&msg, client).await.is_ok() {
+            forwarder.traffic.add_received(payload.len() as u64);
+            forwarder.bytes.received(payload.len() as u64);
+        }
+    }
+}
+
+impl Forwarder {
+    /// Look up the domain name of `dest` and send `payload` there
+    async fn forward_resolved(&self, dest: &Destination, payload: &[u8]) {
+        if let Address::Name(name) = &dest.addr {
+            if let Ok(resolved) = self.server.resolve(name).await {
+                self.forward(dest, payload, &resolved).await;
+            }
+        }
+    }
+
+    /// Send a client datagram on if the access rules allow it. `resolved`
+    /// holds the addresses of a domain destination.
+    async fn forward(&self, dest: &Destination, payload: &[u8], resolved: &[IpAddr]) {
+        let allowed = self.server.allowed(&Request {
+            client: self.client_ip,
+            user: self.user.as_ref(),
+            dest,
+            resolved,
+            cmd: Cmd::Udp,
+        });
+
+        if !allowed {
+            return;
+        }
+
+        let target = match dest.addr {
+            Address::IP(ip) => SocketAddr::new(ip, dest.port),
+            Address::Name(_) => {
+                // the relay socket only reaches addresses of its own family
+                let v4 = self.socket.local_addr().is_ok_and(|a| a.is_ipv4());
+                let ip = resolved.iter().find(|ip| ip.is_ipv4() == v4);
+
+                match ip.or(resolved.first()) {
+                    Some(ip) => SocketAddr::new(*ip, dest.port),
+                    None => return,
+                }
+            }
+        };
+
+        self.up.take(payload.len() as u64).await;
+        self.contacted.lock().unwrap().insert(target);
+
+        // UDP is best effort, a failed send just drops the datagram
+        if self.socket.send_to(payload, target).await.is_ok() {
+            self.traffic.add_sent(payload.len() as u64);
+            self.bytes.sent(payload.len() as u64);
+        }
+    }
+}
+
+fn udp_header(from: SocketAddr) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(22);
+
+    // RSV and FRAG
+    buf.extend([0u8, 0, 0]);
+
+    // an IP address always fits
+    Destination::from(from).put_socks5(&mut buf).unwrap();
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// A relay for a client socket on loopback, running in the background
+    async fn start() -> (UdpSocket, SocketAddr) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = "127.0.0.1:1080".parse().unwrap();
+        let server = Arc::new(Server::new(ServerConfig::default(), local));
+
+        // the client leaves DST.ADDR and DST.PORT as zeros
+        let requested = Destination::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+        let mut relay = UdpRelay::bind(
+            local.ip(),
+            client.local_addr().unwrap(),
+            &requested,
+            server,
+            None,
+        )
+        .await
+        .unwrap();
+        let addr = relay.local_addr().unwrap();
+
+        tokio::spawn(async move { relay.run().await });
+        (client, addr)
+    }
+
+    fn datagram(frag: u8, dest: SocketAddr, payload: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::from(&[0, 0, frag][..]);
+        Destination::from(dest).put_socks5(&mut buf).unwrap();
+        buf.extend_from_slice(payload);
+        buf.to_vec()
+    }
+
+    async fn recv(socket: &UdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0u8; 512];
+        let (len, from) = timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some((buf[..len].to_vec(), from))
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_relayed_both_ways() {
+        let (client, relay) = start().await;
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+
+        client
+            .send_to(&datagram(0, remote_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        let (payload, relay_out) = recv(&remote).await.unwrap();
+        assert_eq!(payload, b"ping");
+
+        remote.send_to(b"pong", relay_out).await.unwrap();
+        let (reply, from) = recv(&client).await.unwrap();
+        assert_eq!(from, relay);
+        assert_eq!(reply, datagram(0, remote_addr, b"pong"));
+    }
+
+    #[tokio::test]
+    async fn fragments_are_dropped() {
+        let (client, relay) = start().await;
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+
+        client
+            .send_to(&datagram(1, remote_addr, b"fragment"), relay)
+            .await
+            .unwrap();
+        client
+            .send_to(&datagram(0, remote_addr, b"whole"), relay)
+            .await
+            .unwrap();
+
+        assert_eq!(recv(&remote).await.unwrap().0, b"whole");
+        assert!(recv(&remote).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_contacted_hosts_reach_the_client() {
+        let (client, relay) = start().await;
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        client
+            .send_to(&datagram(0, remote_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        let (_, relay_out) = recv(&remote).await.unwrap();
+
+        stranger.send_to(b"spoofed", relay_out).await.unwrap();
+        remote.send_to(b"pong", relay_out).await.unwrap();
+
+        assert_eq!(
+            recv(&client).await.unwrap().0,
+            datagram(0, remote_addr, b"pong")
+        );
+        assert!(recv(&client).await.is_none());
+    }
+}
@@ -0,0 +1,53 @@
+use std::future::pending;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+use std::time::{Duration, SystemTime};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often watched files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Call `reload` whenever one of `paths` changes or the process receives
+/// SIGHUP, until `target` is dropped
+pub(crate) async fn reload_on_change<T>(paths: Vec<PathBuf>, target: Weak<T>, reload: impl Fn(&T)) {
+    #[cfg(unix)]
+    let mut hangup = signal(SignalKind::hangup()).ok();
+
+    let times = || paths.iter().map(|p| modified(p)).collect::<Vec<_>>();
+    let mut last_modified = times();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+    while target.strong_count() > 0 {
+        // made anew each time, a finished future can't be polled again
+        let hangup = async {
+            #[cfg(unix)]
+            if let Some(hangup) = hangup.as_mut() {
+                hangup.recv().await;
+                return;
+            }
+
+            pending::<()>().await
+        };
+
+        tokio::select! {
+            _ = interval.tick() => {
+                let now = times();
+                if now == last_modified {
+                    continue;
+                }
+                last_modified = now;
+            }
+            _ = hangup => {}
+        }
+
+        match target.upgrade() {
+            Some(target) => reload(&target),
+            None => break,
+        }
+    }
+}